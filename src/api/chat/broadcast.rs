@@ -0,0 +1,184 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::prelude::*;
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::api::chat::errors::{ChatMessageStreamError, ChatSubscriberError};
+use crate::api::chat::stream::{ChatMessageStream, CHAT_MESSAGES_BUFFER};
+use crate::api::chat::structs::ChatMessage;
+
+type BroadcastItem = Result<ChatMessage, Arc<ChatMessageStreamError>>;
+
+// Shares one chat stream between multiple independent subscribers.
+// Every subscriber gets every message received after it subscribed.
+#[derive(Debug)]
+pub struct ChatMessageBroadcast {
+    cancellation_token: CancellationToken,
+    // Kept only to create new receivers with `resubscribe`
+    receiver: broadcast::Receiver<BroadcastItem>,
+}
+
+impl ChatMessageBroadcast {
+    pub fn new(stream: ChatMessageStream) -> Self {
+        Self::with_capacity(stream, CHAT_MESSAGES_BUFFER)
+    }
+
+    // Every subscriber can fall behind by `capacity` messages before
+    // it starts to skip them and receives `ChatSubscriberError::Lagged`
+    pub fn with_capacity(mut stream: ChatMessageStream, capacity: usize) -> Self {
+        let cancellation_token = CancellationToken::new();
+        // Broadcast channel of zero capacity panics
+        let (sender, receiver) = broadcast::channel::<BroadcastItem>(capacity.max(1));
+
+        let token = cancellation_token.clone();
+        tokio::spawn(async move {
+            loop {
+                select! {
+                    _ = token.cancelled() => break,
+                    msg = stream.next() => match msg {
+                        Some(msg) => {
+                            // Error only means there are no subscribers at the moment
+                            sender.send(msg.map_err(Arc::new)).ok();
+                        }
+                        None => break,
+                    },
+                }
+            }
+            // Dropping the sender ends all subscriber streams
+        });
+
+        Self {
+            cancellation_token,
+            receiver,
+        }
+    }
+
+    // Create a new subscriber which receives messages starting from now
+    pub fn subscribe(&self) -> ChatSubscriber {
+        ChatSubscriber::new(self.receiver.resubscribe())
+    }
+
+    // Close the underlying chat stream. All subscribers will return `None` after
+    // receiving already buffered messages.
+    //
+    // Automatically called on drop. Calling multiple times has no effect.
+    pub fn close(&self) {
+        self.cancellation_token.cancel()
+    }
+}
+
+impl Drop for ChatMessageBroadcast {
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+impl ChatMessageStream {
+    // Turn the stream into a handle which can be subscribed to multiple times
+    pub fn into_broadcast(self) -> ChatMessageBroadcast {
+        ChatMessageBroadcast::new(self)
+    }
+}
+
+// A single subscriber of `ChatMessageBroadcast`
+pub struct ChatSubscriber {
+    lagged: Arc<AtomicU64>,
+    messages: Pin<Box<dyn Stream<Item=Result<ChatMessage, ChatSubscriberError>> + Send>>,
+}
+
+impl ChatSubscriber {
+    fn new(receiver: broadcast::Receiver<BroadcastItem>) -> Self {
+        let lagged = Arc::new(AtomicU64::new(0));
+        let messages = stream::unfold(
+            (receiver, lagged.clone()),
+            |(mut receiver, lagged)| async move {
+                let item = match receiver.recv().await {
+                    Ok(msg) => msg.map_err(ChatSubscriberError::Stream),
+                    Err(RecvError::Lagged(count)) => {
+                        lagged.fetch_add(count, Ordering::Relaxed);
+                        Err(ChatSubscriberError::Lagged(count))
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                Some((item, (receiver, lagged)))
+            },
+        );
+
+        Self {
+            lagged,
+            messages: Box::pin(messages),
+        }
+    }
+
+    // Total number of messages skipped by this subscriber because it was too slow
+    pub fn lagged(&self) -> u64 {
+        self.lagged.load(Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for ChatSubscriber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChatSubscriber")
+            .field("lagged", &self.lagged())
+            .finish()
+    }
+}
+
+impl Stream for ChatSubscriber {
+    type Item = Result<ChatMessage, ChatSubscriberError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.messages.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::chat::events::ChatEvents;
+    use crate::api::chat::testing::{chat, connect, send_chats};
+
+    #[tokio::test]
+    async fn every_subscriber_gets_every_message() {
+        let (stream, server) = connect(&ChatEvents::new()).await;
+        let broadcast = stream.into_broadcast();
+        let mut first = broadcast.subscribe();
+        let mut second = broadcast.subscribe();
+        send_chats(&server, 42, vec![chat("a", "one"), chat("b", "two")]);
+
+        for subscriber in [&mut first, &mut second] {
+            let a = subscriber.next().await.unwrap().unwrap();
+            let b = subscriber.next().await.unwrap().unwrap();
+            assert_eq!((a.message_id.as_str(), b.message_id.as_str()), ("a", "b"));
+        }
+
+        broadcast.close();
+        assert!(first.next().await.is_none());
+        assert!(second.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_is_told_about_skipped_messages() {
+        let (stream, server) = connect(&ChatEvents::new()).await;
+        // Zero is taken as one
+        let broadcast = ChatMessageBroadcast::with_capacity(stream, 0);
+        let mut slow = broadcast.subscribe();
+        let mut fast = broadcast.subscribe();
+        send_chats(&server, 42, vec![chat("a", "one"), chat("b", "two"), chat("c", "three")]);
+        // Once the other subscriber sees the last message, all of them went through the channel
+        while !matches!(fast.next().await, Some(Ok(msg)) if msg.message_id == "c") {}
+
+        assert!(matches!(slow.next().await, Some(Err(ChatSubscriberError::Lagged(2)))));
+        assert_eq!(slow.lagged(), 2);
+        assert_eq!(slow.next().await.unwrap().unwrap().message_id, "c");
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+
+// A part of chat message content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentSegment {
+    // Plain text, including whitespaces between other segments
+    Text(String),
+
+    // Emote written as ":emoteName", contains name without colon
+    Emote(String),
+
+    // Mention written as "@nick", contains nick without "@"
+    Mention(String),
+}
+
+// Content of a chat message split into text, emote and mention segments
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MessageContent {
+    pub segments: Vec<ContentSegment>,
+}
+
+impl MessageContent {
+    // Split content without emote validation: every ":name" is treated as emote
+    pub fn parse(content: &str) -> Self {
+        Self::tokenize(content, None)
+    }
+
+    // Split content, treating ":name" as emote only if name is in the given emote set.
+    // Unknown emotes are left as text.
+    pub fn parse_with_emotes(content: &str, emotes: &HashSet<String>) -> Self {
+        Self::tokenize(content, Some(emotes))
+    }
+
+    fn tokenize(content: &str, emotes: Option<&HashSet<String>>) -> Self {
+        let chars: Vec<char> = content.chars().collect();
+        let mut segments: Vec<ContentSegment> = vec![];
+        let mut text = String::new();
+        // Emotes and mentions may start only at the beginning of content, after a whitespace
+        // or right after another emote (like ":emote1:emote2")
+        let mut at_boundary = true;
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            if at_boundary && (c == ':' || c == '@') {
+                let name: String = chars[i + 1..]
+                    .iter()
+                    .take_while(|c| is_name_char(**c))
+                    .collect();
+                let accepted = !name.is_empty() && match c {
+                    ':' => emotes.is_none_or(|set| set.contains(&name)),
+                    _ => true,
+                };
+                if accepted {
+                    if !text.is_empty() {
+                        segments.push(ContentSegment::Text(std::mem::take(&mut text)));
+                    }
+                    i += 1 + name.chars().count();
+                    at_boundary = c == ':';
+                    segments.push(match c {
+                        ':' => ContentSegment::Emote(name),
+                        _ => ContentSegment::Mention(name),
+                    });
+                    continue;
+                }
+            }
+            text.push(c);
+            at_boundary = c.is_whitespace();
+            i += 1;
+        }
+        if !text.is_empty() {
+            segments.push(ContentSegment::Text(text));
+        }
+        Self { segments }
+    }
+
+    pub fn emotes(&self) -> impl Iterator<Item=&str> {
+        self.segments.iter().filter_map(|s| match s {
+            ContentSegment::Emote(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn mentions(&self) -> impl Iterator<Item=&str> {
+        self.segments.iter().filter_map(|s| match s {
+            ContentSegment::Mention(nick) => Some(nick.as_str()),
+            _ => None,
+        })
+    }
+
+    // Whether the message consists of emotes only (whitespaces are ignored)
+    pub fn is_emote_only(&self) -> bool {
+        let mut has_emotes = false;
+        for segment in &self.segments {
+            match segment {
+                ContentSegment::Emote(_) => has_emotes = true,
+                ContentSegment::Text(text) if text.trim().is_empty() => {}
+                _ => return false,
+            }
+        }
+        has_emotes
+    }
+
+    // Whether the given nick is mentioned. Comparison is case-insensitive.
+    pub fn mentions_nick(&self, nick: &str) -> bool {
+        let nick = nick.trim_start_matches('@');
+        self.mentions().any(|m| m.eq_ignore_ascii_case(nick))
+    }
+
+    // Text without emotes. Mentions are kept as "@nick", whitespaces are collapsed.
+    pub fn plain_text(&self) -> String {
+        let mut result = String::new();
+        for segment in &self.segments {
+            match segment {
+                ContentSegment::Text(text) => result.push_str(text),
+                ContentSegment::Mention(nick) => {
+                    result.push('@');
+                    result.push_str(nick);
+                }
+                ContentSegment::Emote(_) => result.push(' '),
+            }
+        }
+        result.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ContentSegment::{Emote, Mention, Text};
+
+    fn text(s: &str) -> ContentSegment {
+        Text(s.to_string())
+    }
+
+    fn emote(s: &str) -> ContentSegment {
+        Emote(s.to_string())
+    }
+
+    fn mention(s: &str) -> ContentSegment {
+        Mention(s.to_string())
+    }
+
+    #[test]
+    fn emote_chain() {
+        assert_eq!(
+            MessageContent::parse(":a:b_1 hi").segments,
+            vec![emote("a"), emote("b_1"), text(" hi")],
+        );
+        assert_eq!(MessageContent::parse("::a").segments, vec![text("::a")]);
+    }
+
+    #[test]
+    fn markers_inside_word_are_text() {
+        assert_eq!(
+            MessageContent::parse("mail@host 12:30 @nick:wave").segments,
+            vec![text("mail@host 12:30 "), mention("nick"), text(":wave")],
+        );
+        assert_eq!(MessageContent::parse("a @ b :").segments, vec![text("a @ b :")]);
+    }
+
+    #[test]
+    fn unknown_emotes_are_text() {
+        let emotes = HashSet::from(["known".to_string()]);
+        assert_eq!(
+            MessageContent::parse_with_emotes(":known:unknown :unknown @x", &emotes).segments,
+            vec![emote("known"), text(":unknown :unknown "), mention("x")],
+        );
+    }
+
+    #[test]
+    fn emote_only() {
+        assert!(MessageContent::parse(" :a :b:c ").is_emote_only());
+        assert!(!MessageContent::parse(":a hi").is_emote_only());
+        assert!(!MessageContent::parse(":a @b").is_emote_only());
+        assert!(!MessageContent::parse("  ").is_emote_only());
+        assert!(!MessageContent::parse("").is_emote_only());
+    }
+
+    #[test]
+    fn mentioned_nick() {
+        let content = MessageContent::parse("hi @SomeNick!");
+        assert!(content.mentions_nick("somenick"));
+        assert!(content.mentions_nick("@SOMENICK"));
+        assert!(!content.mentions_nick("some"));
+        assert_eq!(content.mentions().collect::<Vec<_>>(), ["SomeNick"]);
+    }
+
+    #[test]
+    fn plain_text_collapses_whitespace() {
+        assert_eq!(MessageContent::parse("  hi:x  :a:b  there @me\n ").plain_text(), "hi:x there @me");
+        assert_eq!(MessageContent::parse(":a").plain_text(), "");
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, io, sync::Arc};
 
 use async_tungstenite::tungstenite::{self, protocol::CloseFrame};
 
@@ -13,6 +13,35 @@ pub enum ChatConnectError {
 
     // The websocket closed before we could connect
     SocketClosed,
+
+    // Error opening a local source of frames, like a recorded session
+    Io(io::Error),
+
+    // TCP connection to the chat server took too long
+    TcpTimeout,
+
+    // TLS handshake took too long
+    TlsTimeout,
+
+    // WebSocket handshake took too long
+    WebSocketTimeout,
+
+    // The server didn't answer our AUTH message in time
+    AuthTimeout,
+
+    // The server rejected the chat token
+    AuthRejected(String),
+}
+
+impl ChatConnectError {
+    // Whether another connection attempt may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::WebSocket(_) | Self::SocketClosed | Self::AuthTimeout => true,
+            Self::TcpTimeout | Self::TlsTimeout | Self::WebSocketTimeout => true,
+            Self::Serde(_) | Self::Io(_) | Self::AuthRejected(_) => false,
+        }
+    }
 }
 
 impl From<tungstenite::Error> for ChatConnectError {
@@ -27,12 +56,24 @@ impl From<serde_json::Error> for ChatConnectError {
     }
 }
 
+impl From<io::Error> for ChatConnectError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
 impl Display for ChatConnectError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::WebSocket(e) => e.fmt(f),
             Self::Serde(e) => e.fmt(f),
             Self::SocketClosed => write!(f, "socket closed"),
+            Self::Io(e) => e.fmt(f),
+            Self::TcpTimeout => write!(f, "connection to chat server timed out"),
+            Self::TlsTimeout => write!(f, "chat server TLS handshake timed out"),
+            Self::WebSocketTimeout => write!(f, "chat socket WebSocket handshake timed out"),
+            Self::AuthTimeout => write!(f, "chat server didn't answer the auth message in time"),
+            Self::AuthRejected(e) => write!(f, "chat token rejected: {}", e),
         }
     }
 }
@@ -43,6 +84,10 @@ impl Error for ChatConnectError {
             Self::WebSocket(e) => Some(e),
             Self::Serde(e) => Some(e),
             Self::SocketClosed => None,
+            Self::Io(e) => Some(e),
+            Self::TcpTimeout | Self::TlsTimeout | Self::WebSocketTimeout => None,
+            Self::AuthTimeout => None,
+            Self::AuthRejected(_) => None,
         }
     }
 }
@@ -103,3 +148,53 @@ impl Error for ChatMessageStreamError {
         }
     }
 }
+
+// Errors that can be received by a single subscriber of `ChatMessageBroadcast`
+#[derive(Debug, Clone)]
+pub enum ChatSubscriberError {
+    // The subscriber was too slow and the given number of messages was skipped
+    Lagged(u64),
+
+    // The underlying chat stream failed. Every subscriber receives the same error.
+    Stream(Arc<ChatMessageStreamError>),
+}
+
+impl Display for ChatSubscriberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lagged(count) => {
+                write!(f, "subscriber lagged behind, {} messages skipped", count)
+            }
+            Self::Stream(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ChatSubscriberError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Lagged(_) => None,
+            Self::Stream(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+// Error of a single channel stream of `MultiChannelChat`.
+// The channel is left after its stream fails.
+#[derive(Debug)]
+pub struct ChannelStreamError {
+    pub channel_id: i32,
+    pub error: ChatMessageStreamError,
+}
+
+impl Display for ChannelStreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "channel {}: {}", self.channel_id, self.error)
+    }
+}
+
+impl Error for ChannelStreamError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
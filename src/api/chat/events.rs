@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{broadcast, watch};
+
+const CHAT_EVENTS_BUFFER: usize = 16;
+
+// Connection lifecycle events reported alongside chat messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatConnectionEvent {
+    // Opening the socket to the chat server
+    Connecting,
+
+    // Server accepted the chat token, chat messages will be received from now
+    Authenticated,
+
+    // Previous connection attempt failed, trying again. Attempts are counted from 1.
+    Reconnecting {
+        attempt: u32,
+    },
+
+    // Socket was closed by us, by the server or because of an error
+    Disconnected {
+        reason: String,
+    },
+
+    // Time between sending a ping and receiving its pong
+    PingLatency(Duration),
+}
+
+// Side channel of `ChatConnectionEvent`s for a chat connection.
+//
+// Can be created before connecting to not miss `Connecting` and `Authenticated` events,
+// see `ChatMessageStream::connect_with_events`.
+#[derive(Debug, Clone)]
+pub struct ChatEvents {
+    events: broadcast::Sender<ChatConnectionEvent>,
+    connected: Arc<watch::Sender<bool>>,
+    // Whether `Disconnected` was sent for the current connection attempt
+    disconnect_reported: Arc<AtomicBool>,
+}
+
+impl Default for ChatEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatEvents {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(CHAT_EVENTS_BUFFER);
+        let (connected, _) = watch::channel(false);
+        Self {
+            events,
+            connected: Arc::new(connected),
+            disconnect_reported: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Receive events sent after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    // Whether the socket is currently authenticated and not disconnected
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    // Watch for connected state changes, e.g. to pause timers while disconnected
+    pub fn connected(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    pub(crate) fn send(&self, event: ChatConnectionEvent) {
+        let duplicate = match &event {
+            ChatConnectionEvent::Connecting => {
+                self.disconnect_reported.store(false, Ordering::SeqCst);
+                false
+            }
+            ChatConnectionEvent::Authenticated => {
+                self.connected.send_replace(true);
+                false
+            }
+            // Socket reader, writer and the failed connection attempt all report disconnection,
+            // send it only once per attempt. Attempts failed before authentication are reported too.
+            ChatConnectionEvent::Disconnected { .. } => {
+                self.connected.send_replace(false);
+                self.disconnect_reported.swap(true, Ordering::SeqCst)
+            }
+            _ => false,
+        };
+        if duplicate {
+            return;
+        }
+        // Error only means there are no subscribers at the moment
+        self.events.send(event).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disconnected() -> ChatConnectionEvent {
+        ChatConnectionEvent::Disconnected { reason: "closed".to_string() }
+    }
+
+    #[test]
+    fn failed_connect_is_reported_once() {
+        let events = ChatEvents::new();
+        let mut received = events.subscribe();
+        events.send(ChatConnectionEvent::Connecting);
+        events.send(disconnected());
+        events.send(disconnected());
+        events.send(ChatConnectionEvent::Connecting);
+        events.send(ChatConnectionEvent::Authenticated);
+        assert!(events.is_connected());
+        events.send(disconnected());
+        events.send(disconnected());
+        assert!(!events.is_connected());
+
+        let mut all = vec![];
+        while let Ok(event) = received.try_recv() {
+            all.push(event);
+        }
+        assert_eq!(all, vec![
+            ChatConnectionEvent::Connecting,
+            disconnected(),
+            ChatConnectionEvent::Connecting,
+            ChatConnectionEvent::Authenticated,
+            disconnected(),
+        ]);
+    }
+}
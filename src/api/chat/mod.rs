@@ -1,3 +1,12 @@
 pub mod stream;
 pub mod structs;
 pub mod errors;
+pub mod content;
+pub mod broadcast;
+pub mod events;
+pub mod multi;
+pub mod queue;
+pub mod record;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod transport;
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::prelude::*;
+use tokio::{select, sync::mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::api::chat::errors::ChannelStreamError;
+use crate::api::chat::stream::{ChatMessageStream, CHAT_MESSAGES_BUFFER};
+use crate::api::chat::structs::ChatMessage;
+use crate::api::client::API;
+
+type ChannelItem = Result<ChannelChatMessage, ChannelStreamError>;
+
+// Chat message tagged with the channel it was sent in
+#[derive(Debug, Clone)]
+pub struct ChannelChatMessage {
+    pub channel_id: i32,
+    pub message: ChatMessage,
+}
+
+#[derive(Debug)]
+struct JoinedChannel {
+    // Distinguishes a rejoined channel from the previous connection to it
+    generation: u64,
+    cancellation_token: CancellationToken,
+}
+
+// Handle for joining and leaving channels of a `MultiChannelMessageStream`.
+// Holds one chat socket per channel. Can be cloned and used while the stream is being read.
+#[derive(Debug, Clone)]
+pub struct MultiChannelChat {
+    channels: Arc<Mutex<HashMap<i32, JoinedChannel>>>,
+    generation: Arc<AtomicU64>,
+    sender: mpsc::Sender<ChannelItem>,
+}
+
+// Messages of all joined channels merged into one stream.
+//
+// Ends when every `MultiChannelChat` handle is dropped and all channels are left.
+#[derive(Debug)]
+pub struct MultiChannelMessageStream {
+    messages: mpsc::Receiver<ChannelItem>,
+}
+
+impl MultiChannelChat {
+    pub fn new() -> (MultiChannelChat, MultiChannelMessageStream) {
+        let (sender, receiver) = mpsc::channel(CHAT_MESSAGES_BUFFER);
+        let chat = MultiChannelChat {
+            channels: Default::default(),
+            generation: Default::default(),
+            sender,
+        };
+        (chat, MultiChannelMessageStream { messages: receiver })
+    }
+
+    // Connect to chat of the channel. Rejoining an already joined channel reconnects it.
+    pub async fn join(&self, api: &mut API, channel_id: i32) -> Result<(), Box<dyn Error>> {
+        let stream = api.chat_messages_for_channel(channel_id).await?;
+        self.join_stream(channel_id, stream);
+        Ok(())
+    }
+
+    // Merge an already connected chat stream of the channel
+    pub fn join_stream(&self, channel_id: i32, mut stream: ChatMessageStream) {
+        let cancellation_token = CancellationToken::new();
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+
+        let previous = self.channels.lock().unwrap().insert(channel_id, JoinedChannel {
+            generation,
+            cancellation_token: cancellation_token.clone(),
+        });
+        if let Some(previous) = previous {
+            previous.cancellation_token.cancel();
+        }
+
+        let channels = self.channels.clone();
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            loop {
+                let msg = select! {
+                    _ = cancellation_token.cancelled() => break,
+                    msg = stream.next() => msg,
+                };
+                let item = match msg {
+                    Some(Ok(message)) => Ok(ChannelChatMessage {
+                        // Historic messages come without channel info
+                        channel_id: message.channel_id.unwrap_or(channel_id),
+                        message,
+                    }),
+                    Some(Err(error)) => Err(ChannelStreamError { channel_id, error }),
+                    None => break,
+                };
+                let failed = item.is_err();
+                // The merged stream may be full and not read, leaving mustn't wait for it
+                let sent = select! {
+                    _ = cancellation_token.cancelled() => break,
+                    sent = sender.send(item) => sent.is_ok(),
+                };
+                if !sent || failed {
+                    break;
+                }
+            }
+
+            let mut channels = channels.lock().unwrap();
+            if channels.get(&channel_id).map(|c| c.generation) == Some(generation) {
+                channels.remove(&channel_id);
+            }
+        });
+    }
+
+    // Disconnect from chat of the channel. Returns `false` if the channel wasn't joined.
+    pub fn leave(&self, channel_id: i32) -> bool {
+        match self.channels.lock().unwrap().remove(&channel_id) {
+            Some(channel) => {
+                channel.cancellation_token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Disconnect from all channels
+    pub fn leave_all(&self) {
+        for (_, channel) in self.channels.lock().unwrap().drain() {
+            channel.cancellation_token.cancel();
+        }
+    }
+
+    // Ids of currently joined channels
+    pub fn channels(&self) -> Vec<i32> {
+        self.channels.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn is_joined(&self, channel_id: i32) -> bool {
+        self.channels.lock().unwrap().contains_key(&channel_id)
+    }
+}
+
+impl Stream for MultiChannelMessageStream {
+    type Item = ChannelItem;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::api::chat::events::ChatEvents;
+    use crate::api::chat::testing::{chat, connect, send_chats};
+
+    #[tokio::test]
+    async fn messages_are_tagged_with_channel() {
+        let (chat_handle, mut merged) = MultiChannelChat::new();
+        let (first, first_server) = connect(&ChatEvents::new()).await;
+        let (second, second_server) = connect(&ChatEvents::new()).await;
+        chat_handle.join_stream(1, first);
+        chat_handle.join_stream(2, second);
+        let mut joined = chat_handle.channels();
+        joined.sort();
+        assert_eq!(joined, [1, 2]);
+
+        send_chats(&first_server, 1, vec![chat("a", "first")]);
+        let msg = merged.next().await.unwrap().unwrap();
+        assert_eq!((msg.channel_id, msg.message.message_id.as_str()), (1, "a"));
+        send_chats(&second_server, 2, vec![chat("b", "second")]);
+        let msg = merged.next().await.unwrap().unwrap();
+        assert_eq!((msg.channel_id, msg.message.message_id.as_str()), (2, "b"));
+
+        // Historic messages have no channel info, the joined channel is used
+        second_server.send_json(&serde_json::json!({
+            "type": "CHAT",
+            "data": {"eid": "e", "chats": [chat("c", "historic")]},
+        }));
+        let msg = merged.next().await.unwrap().unwrap();
+        assert_eq!((msg.channel_id, msg.message.message_id.as_str()), (2, "c"));
+    }
+
+    #[tokio::test]
+    async fn left_channel_is_disconnected() {
+        let (chat_handle, mut merged) = MultiChannelChat::new();
+        let (stream, mut server) = connect(&ChatEvents::new()).await;
+        chat_handle.join_stream(1, stream);
+
+        assert!(chat_handle.leave(1));
+        assert!(!chat_handle.is_joined(1));
+        assert!(!chat_handle.leave(1));
+        assert_eq!(timeout(Duration::from_secs(5), server.recv()).await.unwrap(), None);
+
+        drop(chat_handle);
+        assert!(merged.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn channel_is_left_while_merged_stream_is_full() {
+        let (chat_handle, _merged) = MultiChannelChat::new();
+        let (stream, mut server) = connect(&ChatEvents::new()).await;
+        chat_handle.join_stream(1, stream);
+        let chats = (0..CHAT_MESSAGES_BUFFER * 2).map(|i| chat(&i.to_string(), "spam")).collect();
+        send_chats(&server, 1, chats);
+        // Let the channel task fill the merged stream, nobody reads it
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(chat_handle.leave(1));
+        assert_eq!(timeout(Duration::from_secs(5), server.recv()).await.unwrap(), None);
+    }
+}
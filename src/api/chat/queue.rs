@@ -0,0 +1,265 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use tokio::sync::Notify;
+
+// What to do with a new chat message when the buffer of the stream is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    // Wait until the consumer reads a message. The socket reader is paused meanwhile,
+    // so pings aren't answered and a slow consumer may lose the connection.
+    #[default]
+    Block,
+
+    // Remove the oldest buffered message to make room for the new one
+    DropOldest,
+
+    // Discard the new message
+    DropNewest,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    // Notified when the receiver takes a message or is dropped
+    space: Notify,
+    dropped: AtomicU64,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl<T> Shared<T> {
+    fn push(&self, state: &mut State<T>, item: T) {
+        state.items.push_back(item);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Bounded queue of chat messages applying `OverflowPolicy` when full
+pub(crate) fn channel<T>(capacity: usize, policy: OverflowPolicy) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+            waker: None,
+        }),
+        space: Notify::new(),
+        dropped: AtomicU64::new(0),
+        // Zero capacity would block forever
+        capacity: capacity.max(1),
+        policy,
+    });
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+#[derive(Debug)]
+pub(crate) struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueSender<T> {
+    // Push according to the overflow policy. Fails only if the receiver was dropped.
+    pub(crate) async fn send(&self, item: T) -> Result<(), T> {
+        loop {
+            // Created before checking for space, so a notification in between isn't lost
+            let space = self.shared.space.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if !state.receiver_alive {
+                    return Err(item);
+                }
+                if state.items.len() < self.shared.capacity {
+                    self.shared.push(&mut state, item);
+                    return Ok(());
+                }
+                match self.shared.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                        self.shared.push(&mut state, item);
+                        return Ok(());
+                    }
+                    OverflowPolicy::DropNewest => {
+                        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                }
+            }
+            space.await;
+        }
+    }
+
+    // Push ignoring the capacity. Used for terminal errors which must never be dropped.
+    pub(crate) fn send_forced(&self, item: T) -> Result<(), T> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.receiver_alive {
+            return Err(item);
+        }
+        self.shared.push(&mut state, item);
+        Ok(())
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Let the receiver see the end of the stream
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            self.shared.space.notify_one();
+            return Poll::Ready(Some(item));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    // Number of messages discarded because the buffer was full
+    pub(crate) fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    // Number of messages waiting to be read
+    pub(crate) fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_alive = false;
+        // Wake every sender blocked on the full buffer, there may be several clones
+        self.shared.space.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    async fn recv<T>(receiver: &mut QueueReceiver<T>) -> Option<T> {
+        poll_fn(|cx| receiver.poll_recv(cx)).await
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_space() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::Block);
+        sender.send(1).await.unwrap();
+        sender.send(2).await.unwrap();
+        let blocked = tokio::spawn(async move {
+            sender.send(3).await.unwrap();
+            sender
+        });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+        assert_eq!(receiver.len(), 2);
+
+        assert_eq!(recv(&mut receiver).await, Some(1));
+        let sender = timeout(Duration::from_secs(1), blocked).await.unwrap().unwrap();
+        drop(sender);
+        assert_eq!(recv(&mut receiver).await, Some(2));
+        assert_eq!(recv(&mut receiver).await, Some(3));
+        assert_eq!(recv(&mut receiver).await, None);
+        assert_eq!(receiver.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_counts_dropped() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropOldest);
+        for i in 1..=5 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        assert_eq!(receiver.dropped(), 3);
+        assert_eq!(recv(&mut receiver).await, Some(4));
+        assert_eq!(recv(&mut receiver).await, Some(5));
+        assert_eq!(recv(&mut receiver).await, None);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_counts_dropped() {
+        let (sender, mut receiver) = channel(2, OverflowPolicy::DropNewest);
+        for i in 1..=5 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        assert_eq!(receiver.dropped(), 3);
+        assert_eq!(recv(&mut receiver).await, Some(1));
+        assert_eq!(recv(&mut receiver).await, Some(2));
+        assert_eq!(recv(&mut receiver).await, None);
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_unblocks_senders() {
+        let (sender, receiver) = channel(1, OverflowPolicy::Block);
+        sender.send(0).await.unwrap();
+        let other = sender.clone();
+        let blocked = [
+            tokio::spawn(async move { sender.send(1).await }),
+            tokio::spawn(async move { other.send(2).await }),
+        ];
+        tokio::task::yield_now().await;
+        drop(receiver);
+        for (task, item) in blocked.into_iter().zip([1, 2]) {
+            let result = timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+            assert_eq!(result, Err(item));
+        }
+    }
+
+    #[tokio::test]
+    async fn forced_send_ignores_capacity() {
+        let (sender, mut receiver) = channel(1, OverflowPolicy::DropNewest);
+        sender.send(1).await.unwrap();
+        sender.send_forced(2).unwrap();
+        assert_eq!(receiver.len(), 2);
+        assert_eq!(receiver.dropped(), 0);
+        assert_eq!(recv(&mut receiver).await, Some(1));
+        assert_eq!(recv(&mut receiver).await, Some(2));
+        drop(receiver);
+        assert_eq!(sender.send_forced(3), Err(3));
+    }
+}
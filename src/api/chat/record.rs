@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::{self, Message};
+use chrono::Utc;
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+// Kind of recorded socket frame
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameKind {
+    Text,
+    Binary,
+    Close,
+}
+
+// A single raw frame received from the chat socket. Stored as one line of a JSONL file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordedFrame {
+    // Receive time, milliseconds since unix epoch
+    pub time_ms: i64,
+    pub kind: FrameKind,
+    // Frame payload. Binary frames are stored as UTF-8, Trovo sends json in them anyway.
+    #[serde(default)]
+    pub data: String,
+}
+
+impl RecordedFrame {
+    // Returns `None` for frames which aren't worth recording (pings, pongs and raw frames)
+    fn from_message(msg: &Message) -> Option<Self> {
+        let (kind, data) = match msg {
+            Message::Text(text) => (FrameKind::Text, text.clone()),
+            Message::Binary(bytes) => (FrameKind::Binary, String::from_utf8_lossy(bytes).into_owned()),
+            Message::Close(frame) => (
+                FrameKind::Close,
+                frame.as_ref().map(|f| f.reason.to_string()).unwrap_or_default(),
+            ),
+            _ => return None,
+        };
+        Some(Self {
+            time_ms: Utc::now().timestamp_millis(),
+            kind,
+            data,
+        })
+    }
+
+    fn into_message(self) -> Message {
+        match self.kind {
+            FrameKind::Text => Message::Text(self.data),
+            FrameKind::Binary => Message::Binary(self.data.into_bytes()),
+            FrameKind::Close => Message::Close(None),
+        }
+    }
+}
+
+// Writes every raw frame received by the chat socket reader to a JSONL file.
+//
+// Frames are written by a dedicated thread, so the socket reader never waits for the disk.
+// Pass it with `ChatConnectOptions::recorder`.
+#[derive(Debug, Clone)]
+pub struct ChatRecorder {
+    frames: mpsc::Sender<RecordedFrame>,
+}
+
+impl ChatRecorder {
+    // Create or truncate the file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::start(File::create(path)?)
+    }
+
+    // Append to the file, creating it if it doesn't exist
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::start(File::options().create(true).append(true).open(path)?)
+    }
+
+    // The writer thread stops after all clones of the recorder are dropped and the queue is written
+    fn start(file: File) -> io::Result<Self> {
+        let (frames, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("chat-recorder".to_string())
+            .spawn(move || write_frames(received, BufWriter::new(file)))?;
+        Ok(Self { frames })
+    }
+
+    pub(crate) fn record(&self, msg: &Message) -> io::Result<()> {
+        let frame = match RecordedFrame::from_message(msg) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        self.frames.send(frame).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "chat recorder stopped")
+        })
+    }
+}
+
+fn write_frames(frames: mpsc::Receiver<RecordedFrame>, mut writer: BufWriter<File>) {
+    let write = |writer: &mut BufWriter<File>, frame: &RecordedFrame| -> io::Result<()> {
+        writeln!(writer, "{}", serde_json::to_string(frame)?)
+    };
+    while let Ok(frame) = frames.recv() {
+        let mut result = write(&mut writer, &frame);
+        // Flush once the queue is drained, so that a crash doesn't lose the traffic we want to debug
+        while result.is_ok() {
+            match frames.try_recv() {
+                Ok(frame) => result = write(&mut writer, &frame),
+                Err(_) => break,
+            }
+        }
+        if let Err(err) = result.and_then(|_| writer.flush()) {
+            println!("Cannot record chat frame: {}", err);
+        }
+    }
+}
+
+// How fast recorded frames are fed to the stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    // Keep the original delays between frames
+    RealTime,
+
+    // Divide original delays by the factor, e.g. 10.0 replays 10 times faster
+    Accelerated(f64),
+
+    // No delays at all
+    Instant,
+}
+
+impl ReplaySpeed {
+    fn delay(&self, original: Duration) -> Duration {
+        match self {
+            Self::RealTime => original,
+            Self::Accelerated(factor) if *factor > 0.0 => original.div_f64(*factor),
+            Self::Accelerated(_) | Self::Instant => Duration::ZERO,
+        }
+    }
+}
+
+// Read all frames of a file written by `ChatRecorder`. Empty lines are skipped.
+pub fn read_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedFrame>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut frames = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        frames.push(serde_json::from_str(&line)?);
+    }
+    Ok(frames)
+}
+
+// Socket-like stream of recorded frames, delayed according to the speed
+pub(crate) fn replay_frames(
+    frames: Vec<RecordedFrame>,
+    speed: ReplaySpeed,
+) -> impl Stream<Item=Result<Message, tungstenite::Error>> + Send + Unpin + 'static {
+    let mut previous_time: Option<i64> = None;
+    let delayed = frames.into_iter().map(move |frame| {
+        let gap = previous_time.map_or(0, |previous| (frame.time_ms - previous).max(0));
+        previous_time = Some(frame.time_ms);
+        (speed.delay(Duration::from_millis(gap as u64)), frame)
+    }).collect::<Vec<_>>();
+
+    Box::pin(stream::iter(delayed).then(|(delay, frame)| async move {
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        Ok(frame.into_message())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_written_in_order() {
+        let path = std::env::temp_dir().join(format!("chat-record-{}.jsonl", std::process::id()));
+        let recorder = ChatRecorder::create(&path).unwrap();
+        for i in 0..100 {
+            recorder.record(&Message::Text(i.to_string())).unwrap();
+        }
+        recorder.record(&Message::Ping(vec![])).unwrap();
+        drop(recorder);
+
+        // The writer thread finishes the queue after the last recorder is dropped
+        let mut frames = vec![];
+        for _ in 0..100 {
+            // A line may be half-written while the thread is still busy
+            frames = read_recording(&path).unwrap_or_default();
+            if frames.len() == 100 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).ok();
+        let data: Vec<String> = frames.into_iter().map(|frame| frame.data).collect();
+        assert_eq!(data, (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+}
@@ -1,42 +1,153 @@
 use std::collections::HashMap;
-use std::time::Duration;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use async_tungstenite::{
-    tokio::connect_async,
-    tungstenite::{self, Message},
-};
+use async_tungstenite::tungstenite::{self, Message};
 use chrono::Local;
 use futures::prelude::*;
 use tokio::{
     select,
     sync::{mpsc, oneshot},
-    time::sleep,
+    time::{sleep, timeout},
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::api::chat::errors::ChatConnectError;
 use crate::api::chat::errors::ChatMessageStreamError;
+use crate::api::chat::events::{ChatConnectionEvent, ChatEvents};
+use crate::api::chat::queue::{self, OverflowPolicy, QueueReceiver, QueueSender};
+use crate::api::chat::record::{ChatRecorder, ReplaySpeed};
+use crate::api::chat::transport::{ChatTransport, ReplayTransport, WebSocketTransport};
 use crate::api::chat::structs::{ChatMessage, ChatSocketMessage};
 use crate::utils::utils::random_string;
 
-const CHAT_MESSAGES_BUFFER: usize = 32;
+pub(crate) const CHAT_MESSAGES_BUFFER: usize = 32;
 const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
 
+// Options of the chat connection
+#[derive(Debug, Clone)]
+pub struct ChatConnectOptions {
+    // Side channel to report connection events to
+    pub events: ChatEvents,
+
+    // Write every raw frame received from the socket to a file
+    pub recorder: Option<ChatRecorder>,
+
+    // Deadline for every stage of opening the socket: TCP connection, TLS and WebSocket handshakes
+    pub handshake_timeout: Duration,
+
+    // Deadline for the server to answer our AUTH message
+    pub auth_timeout: Duration,
+
+    // How many times to try again after a failed attempt. Zero disables retries.
+    pub retries: u32,
+
+    // Delay before the first retry, grows linearly with every attempt
+    pub retry_delay: Duration,
+
+    // Number of received chat messages kept until they are read from the stream
+    pub buffer_size: usize,
+
+    // What to do with new messages when the buffer is full
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ChatConnectOptions {
+    fn default() -> Self {
+        Self {
+            events: Default::default(),
+            recorder: None,
+            handshake_timeout: Duration::from_secs(10),
+            auth_timeout: Duration::from_secs(10),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            buffer_size: CHAT_MESSAGES_BUFFER,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
 // A chat of chat messages
 #[derive(Debug)]
 pub struct ChatMessageStream {
     cancellation_token: CancellationToken,
-    messages: mpsc::Receiver<Result<ChatMessage, ChatMessageStreamError>>,
+    messages: QueueReceiver<Result<ChatMessage, ChatMessageStreamError>>,
+    events: ChatEvents,
 }
 
 impl ChatMessageStream {
     // Connect to trovo chat using the given chat token.
-    // FIXME: Sometimes connecting takes too much time and then crashes WebSocket(Protocol(HandshakeIncomplete))
+    //
+    // Hanging handshakes are cut by timeouts and retried, see `ChatConnectOptions`.
     pub async fn connect(chat_token: String) -> Result<ChatMessageStream, ChatConnectError> {
-        let cancellation_token = CancellationToken::new();
+        Self::connect_with_events(chat_token, ChatEvents::new()).await
+    }
+
+    // Same as `connect`, but reports connection events to the given side channel,
+    // so `Connecting` and `Authenticated` can be received too
+    pub async fn connect_with_events(
+        chat_token: String,
+        events: ChatEvents,
+    ) -> Result<ChatMessageStream, ChatConnectError> {
+        let options = ChatConnectOptions {
+            events,
+            ..Default::default()
+        };
+        Self::connect_with_options(chat_token, options).await
+    }
 
-        let (ws_stream, _) = connect_async("wss://open-chat.trovo.live/chat").await?;
-        let (mut writer, reader) = ws_stream.split();
+    pub async fn connect_with_options(
+        chat_token: String,
+        options: ChatConnectOptions,
+    ) -> Result<ChatMessageStream, ChatConnectError> {
+        Self::connect_with_transport(WebSocketTransport::default(), chat_token, options).await
+    }
+
+    // Run the chat over any transport, e.g. `MemoryTransport` in tests.
+    //
+    // The AUTH handshake and pings are skipped for transports which aren't live.
+    pub async fn connect_with_transport<T: ChatTransport>(
+        mut transport: T,
+        chat_token: String,
+        options: ChatConnectOptions,
+    ) -> Result<ChatMessageStream, ChatConnectError> {
+        let mut attempt = 0;
+        loop {
+            let cancellation_token = CancellationToken::new();
+            let result = Self::try_connect(
+                &mut transport, &chat_token, &options, cancellation_token.clone(),
+            ).await;
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    // Stop the socket reader of the failed attempt
+                    cancellation_token.cancel();
+                    options.events.send(ChatConnectionEvent::Disconnected { reason: err.to_string() });
+                    if !transport.can_retry() || !err.is_retryable() || attempt >= options.retries {
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    println!("Cannot connect to chat: {}. Retrying ({}/{})", err, attempt, options.retries);
+                    options.events.send(ChatConnectionEvent::Reconnecting { attempt });
+                    sleep(options.retry_delay * attempt).await;
+                }
+            }
+        }
+    }
+
+    async fn try_connect<T: ChatTransport>(
+        transport: &mut T,
+        chat_token: &str,
+        options: &ChatConnectOptions,
+        cancellation_token: CancellationToken,
+    ) -> Result<ChatMessageStream, ChatConnectError> {
+        let events = options.events.clone();
+        let ping: SharedPing = Default::default();
+        let is_live = transport.is_live();
+
+        events.send(ChatConnectionEvent::Connecting);
+        let (mut writer, reader) = transport.connect(options.handshake_timeout).await?;
         let (
             socket_messages_sender,
             socket_messages_receiver
@@ -44,7 +155,7 @@ impl ChatMessageStream {
         let (
             chat_messages_sender,
             chat_messages_receiver
-        ) = mpsc::channel(CHAT_MESSAGES_BUFFER);
+        ) = queue::channel(options.buffer_size, options.overflow);
         let (
             auth_response_sender,
             auth_response_receiver
@@ -57,40 +168,76 @@ impl ChatMessageStream {
             cancellation_token: cancellation_token.clone(),
             auth: (auth_nonce.clone(), Some(auth_response_sender)),
             chat_messages_sender: chat_messages_sender.clone(),
-            ping: Default::default(),
+            ping: ping.clone(),
+            events: events.clone(),
+            recorder: options.recorder.clone(),
         };
         reader.spawn();
 
-        let msg = serde_json::to_string(&ChatSocketMessage::Auth {
-            nonce: auth_nonce,
-            data: HashMap::from_iter([("token".to_string(), chat_token)]),
-        })?;
-        writer.send(msg.into()).await?;
-
-        auth_response_receiver
-            .await
-            .map_err(|_| ChatConnectError::SocketClosed)??;
+        if is_live {
+            let msg = serde_json::to_string(&ChatSocketMessage::Auth {
+                nonce: auth_nonce,
+                data: HashMap::from_iter([("token".to_string(), chat_token.to_string())]),
+            })?;
+            writer.send(msg.into()).await?;
+
+            timeout(options.auth_timeout, auth_response_receiver)
+                .await
+                .map_err(|_| ChatConnectError::AuthTimeout)?
+                .map_err(|_| ChatConnectError::SocketClosed)??;
+        }
+        events.send(ChatConnectionEvent::Authenticated);
 
         let writer = SocketMessagesWriter {
             writer,
             cancellation_token: cancellation_token.clone(),
             socket_messages_receiver,
             chat_messages_sender,
+            events: events.clone(),
         };
         writer.spawn();
 
-        let pinger = Pinger {
-            ping: Default::default(),
-            socket_messages_sender,
-        };
-        pinger.spawn();
+        if is_live {
+            let pinger = Pinger {
+                ping,
+                socket_messages_sender,
+            };
+            pinger.spawn();
+        }
 
         Ok(ChatMessageStream {
             cancellation_token,
             messages: chat_messages_receiver,
+            events,
         })
     }
 
+    // Feed a file written by `ChatRecorder` through the same decoding pipeline as a live chat.
+    // Nothing is sent anywhere, the stream ends after the last recorded frame.
+    pub async fn replay<P: AsRef<Path>>(
+        path: P,
+        speed: ReplaySpeed,
+    ) -> Result<ChatMessageStream, ChatConnectError> {
+        let transport = ReplayTransport::open(path, speed)?;
+        Self::connect_with_transport(transport, String::new(), Default::default()).await
+    }
+
+    // Side channel of connection lifecycle events
+    pub fn events(&self) -> &ChatEvents {
+        &self.events
+    }
+
+    // Number of chat messages discarded so far because the buffer was full.
+    // Always zero with `OverflowPolicy::Block`.
+    pub fn dropped(&self) -> u64 {
+        self.messages.dropped()
+    }
+
+    // Number of received chat messages which weren't read yet
+    pub fn buffered(&self) -> usize {
+        self.messages.len()
+    }
+
     // Close the chat socket, causing any further calls to `next()` to return `None`.
     //
     // Automatically called on drop. Calling multiple times has no effect.
@@ -129,37 +276,48 @@ struct Ping {
 
     // The last iteration that we got a Pong response to
     acknowledged: u64,
+
+    // When the ping of the last iteration was sent
+    sent_at: Option<Instant>,
 }
 
+type SharedPing = Arc<Mutex<Ping>>;
+
 impl Default for Ping {
     fn default() -> Self {
         Self {
             interval: DEFAULT_PING_INTERVAL,
             iteration: 0,
             acknowledged: 0,
+            sent_at: None,
         }
     }
 }
 
-// TODO: Dynamic state provided by 'SocketMessagesReader::handle_socket_message'
+// Ping state is shared with 'SocketMessagesReader', which updates the interval from Pong responses
 #[derive(Debug)]
 struct Pinger {
-    ping: Ping,
+    ping: SharedPing,
     socket_messages_sender: mpsc::Sender<ChatSocketMessage>,
 }
 
 impl Pinger {
-    fn spawn(mut self) {
+    fn spawn(self) {
         tokio::spawn(async move {
             loop {
-                sleep(self.ping.interval).await;
+                let interval = self.ping.lock().unwrap().interval;
+                sleep(interval).await;
                 println!("-------------Ping sent at {}-------------", Local::now());
-                self.ping.iteration += 1;
+                let iteration = {
+                    let mut ping = self.ping.lock().unwrap();
+                    ping.iteration += 1;
+                    ping.sent_at = Some(Instant::now());
+                    ping.iteration
+                };
 
-                let msg = ChatSocketMessage::Ping { nonce: self.ping.iteration.to_string() };
-                match self.socket_messages_sender.send(msg).await {
-                    Err(_) => panic!("Service unavailable: cannot send ping"),
-                    _ => {}
+                let msg = ChatSocketMessage::Ping { nonce: iteration.to_string() };
+                if self.socket_messages_sender.send(msg).await.is_err() {
+                    panic!("Service unavailable: cannot send ping");
                 };
             };
         });
@@ -170,12 +328,14 @@ impl Pinger {
 struct SocketMessagesReader<R> {
     cancellation_token: CancellationToken,
     reader: R,
-    chat_messages_sender: mpsc::Sender<Result<ChatMessage, ChatMessageStreamError>>,
+    chat_messages_sender: QueueSender<Result<ChatMessage, ChatMessageStreamError>>,
     auth: (
         String,
         Option<oneshot::Sender<Result<(), ChatConnectError>>>,
     ),
-    ping: Ping,
+    ping: SharedPing,
+    events: ChatEvents,
+    recorder: Option<ChatRecorder>,
 }
 
 impl<R> SocketMessagesReader<R>
@@ -187,10 +347,16 @@ impl<R> SocketMessagesReader<R>
             loop {
                 match self.next().await {
                     Ok(Continuation::Stop) => {
+                        self.events.send(ChatConnectionEvent::Disconnected {
+                            reason: "socket closed".to_string(),
+                        });
                         break;
                     }
                     Err(err) => {
-                        self.chat_messages_sender.send(Err(err)).await.ok();
+                        self.events.send(ChatConnectionEvent::Disconnected {
+                            reason: err.to_string(),
+                        });
+                        self.chat_messages_sender.send_forced(Err(err)).ok();
                         break;
                     }
                     _ => {}
@@ -204,11 +370,10 @@ impl<R> SocketMessagesReader<R>
             _ = self.cancellation_token.cancelled() => {
                 Ok(Continuation::Stop)
             }
-            Some(msg) = self.reader.next() => {
-                self.handle_message(msg?).await
-            }
-            else => {
-                Ok(Continuation::Stop)
+            msg = self.reader.next() => match msg {
+                Some(msg) => self.handle_message(msg?).await,
+                // Socket stream has ended, `else` wouldn't fire while cancellation is pending
+                None => Ok(Continuation::Stop),
             }
         }
     }
@@ -217,6 +382,12 @@ impl<R> SocketMessagesReader<R>
         &mut self,
         msg: Message,
     ) -> Result<Continuation, ChatMessageStreamError> {
+        if let Some(recorder) = &self.recorder {
+            // Recording is a debugging aid and must not break the chat
+            if let Err(err) = recorder.record(&msg) {
+                println!("Cannot record chat frame: {}", err);
+            }
+        }
         match msg {
             Message::Text(text) => {
                 let msg: ChatSocketMessage = serde_json::from_str(&text)?;
@@ -238,10 +409,14 @@ impl<R> SocketMessagesReader<R>
 
     async fn handle_socket_message(&mut self, msg: ChatSocketMessage) -> Continuation {
         match msg {
-            ChatSocketMessage::Response { nonce } => {
+            ChatSocketMessage::Response { nonce, error } => {
                 if self.auth.0 == nonce {
                     if let Some(auth) = self.auth.1.take() {
-                        auth.send(Ok(())).ok();
+                        let result = match error {
+                            Some(error) => Err(ChatConnectError::AuthRejected(error)),
+                            None => Ok(()),
+                        };
+                        auth.send(result).ok();
                     }
                 }
                 Continuation::Continue
@@ -253,18 +428,26 @@ impl<R> SocketMessagesReader<R>
                         return Continuation::Continue;
                     }
                 };
+                let mut ping = self.ping.lock().unwrap();
                 // Ignore potentially delayed responses from any old pings
-                if iteration > self.ping.acknowledged {
-                    self.ping.acknowledged = iteration;
-                    self.ping.interval = Duration::from_secs(data.gap);
+                if iteration > ping.acknowledged {
+                    ping.acknowledged = iteration;
+                    ping.interval = Duration::from_secs(data.gap);
+                    if iteration == ping.iteration {
+                        if let Some(sent_at) = ping.sent_at {
+                            self.events.send(ChatConnectionEvent::PingLatency(sent_at.elapsed()));
+                        }
+                    }
                 }
                 Continuation::Continue
             }
             ChatSocketMessage::Chat {
-                channel_info: _,
+                channel_info,
                 data,
             } => {
-                for chat in data.chats {
+                let channel_id = channel_info.and_then(|info| info.channel_id.parse().ok());
+                for mut chat in data.chats {
+                    chat.channel_id = channel_id;
                     if self.chat_messages_sender.send(Ok(chat)).await.is_err() {
                         // Messages receiver must have been dropped and so we just need to cleanup
                         return Continuation::Stop;
@@ -287,7 +470,8 @@ struct SocketMessagesWriter<W> {
     cancellation_token: CancellationToken,
     writer: W,
     socket_messages_receiver: mpsc::Receiver<ChatSocketMessage>,
-    chat_messages_sender: mpsc::Sender<Result<ChatMessage, ChatMessageStreamError>>,
+    chat_messages_sender: QueueSender<Result<ChatMessage, ChatMessageStreamError>>,
+    events: ChatEvents,
 }
 
 impl<W> SocketMessagesWriter<W>
@@ -302,7 +486,10 @@ impl<W> SocketMessagesWriter<W>
                         break;
                     }
                     Err(err) => {
-                        self.chat_messages_sender.send(Err(err)).await.ok();
+                        self.events.send(ChatConnectionEvent::Disconnected {
+                            reason: err.to_string(),
+                        });
+                        self.chat_messages_sender.send_forced(Err(err)).ok();
                         break;
                     }
                     _ => {}
@@ -341,3 +528,147 @@ impl<W> Drop for SocketMessagesWriter<W> {
         self.cancellation_token.cancel();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::chat::testing::{answer_auth, chat, connect, options};
+    use crate::api::chat::transport::MemoryTransport;
+
+    #[tokio::test]
+    async fn auth_handshake() {
+        let events = ChatEvents::new();
+        let mut received = events.subscribe();
+        let (_stream, _server) = connect(&events).await;
+        assert_eq!(received.recv().await.unwrap(), ChatConnectionEvent::Connecting);
+        assert_eq!(received.recv().await.unwrap(), ChatConnectionEvent::Authenticated);
+        assert!(events.is_connected());
+    }
+
+    #[tokio::test]
+    async fn auth_rejected() {
+        let (transport, mut server) = MemoryTransport::pair();
+        let connecting = tokio::spawn(ChatMessageStream::connect_with_transport(
+            transport, "bad".to_string(), options(&ChatEvents::new()),
+        ));
+        answer_auth(&mut server, Some("invalid token")).await;
+        assert!(matches!(
+            connecting.await.unwrap(),
+            Err(ChatConnectError::AuthRejected(error)) if error == "invalid token",
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn auth_timeout() {
+        let (transport, mut server) = MemoryTransport::pair();
+        let connecting = tokio::spawn(ChatMessageStream::connect_with_transport(
+            transport, "token".to_string(), options(&ChatEvents::new()),
+        ));
+        // Received, but never answered
+        server.recv().await.unwrap();
+        assert!(matches!(connecting.await.unwrap(), Err(ChatConnectError::AuthTimeout)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pong_sets_interval_and_latency() {
+        let events = ChatEvents::new();
+        let mut received = events.subscribe();
+        let (_stream, mut server) = connect(&events).await;
+
+        let started = tokio::time::Instant::now();
+        let ping: ChatSocketMessage = server.recv_json().await.unwrap().unwrap();
+        assert!(matches!(&ping, ChatSocketMessage::Ping { nonce } if nonce == "1"));
+        assert_eq!(started.elapsed(), DEFAULT_PING_INTERVAL);
+
+        server.send_json(&json!({"type": "PONG", "nonce": "1", "data": {"gap": 5}}));
+        // Latency is measured with the real clock, which isn't paused
+        let latency = loop {
+            if let ChatConnectionEvent::PingLatency(latency) = received.recv().await.unwrap() {
+                break latency;
+            }
+        };
+        assert!(latency < Duration::from_secs(1));
+
+        // The pinger was already waiting for the second ping, the gap advised by the server
+        // applies from the third one
+        let ping: ChatSocketMessage = server.recv_json().await.unwrap().unwrap();
+        assert!(matches!(&ping, ChatSocketMessage::Ping { nonce } if nonce == "2"));
+        let second = tokio::time::Instant::now();
+        let ping: ChatSocketMessage = server.recv_json().await.unwrap().unwrap();
+        assert!(matches!(&ping, ChatSocketMessage::Ping { nonce } if nonce == "3"));
+        assert_eq!(second.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn chat_batch_is_split_into_messages() {
+        let (mut stream, server) = connect(&ChatEvents::new()).await;
+        server.send_json(&json!({
+            "type": "CHAT",
+            "channel_info": {"channel_id": "42"},
+            "data": {"eid": "e", "chats": [chat("a", "first"), chat("b", "second")]},
+        }));
+        server.send_json(&json!({
+            "type": "CHAT",
+            "data": {"eid": "f", "chats": [chat("c", "historic")]},
+        }));
+
+        let mut messages = vec![];
+        for _ in 0..3 {
+            messages.push(stream.next().await.unwrap().unwrap());
+        }
+        let ids: Vec<(&str, Option<i32>)> = messages.iter()
+            .map(|msg| (msg.message_id.as_str(), msg.channel_id))
+            .collect();
+        assert_eq!(ids, [("a", Some(42)), ("b", Some(42)), ("c", None)]);
+    }
+
+    #[tokio::test]
+    async fn dropped_socket_ends_with_error() {
+        let (mut stream, server) = connect(&ChatEvents::new()).await;
+        server.fail(tungstenite::Error::ConnectionClosed);
+        assert!(matches!(stream.next().await, Some(Err(ChatMessageStreamError::WebSocket(_)))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_shot_transport_is_not_retried() {
+        let (transport, mut server) = MemoryTransport::pair();
+        let options = ChatConnectOptions { retries: 3, ..Default::default() };
+        let auth_timeout = options.auth_timeout;
+        let started = tokio::time::Instant::now();
+        let connecting = tokio::spawn(ChatMessageStream::connect_with_transport(
+            transport, "token".to_string(), options,
+        ));
+        server.recv().await.unwrap();
+        assert!(matches!(connecting.await.unwrap(), Err(ChatConnectError::AuthTimeout)));
+        assert_eq!(started.elapsed(), auth_timeout);
+    }
+
+    // Accepts connections, but never answers
+    async fn silent_server() -> (tokio::net::TcpListener, u16) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    async fn connect_to(url: String) -> ChatConnectError {
+        let options = ChatConnectOptions {
+            handshake_timeout: Duration::from_millis(100),
+            retries: 0,
+            ..Default::default()
+        };
+        ChatMessageStream::connect_with_transport(WebSocketTransport::new(url), String::new(), options)
+            .await
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn handshake_timeouts_tell_the_stage() {
+        let (_listener, port) = silent_server().await;
+        let err = connect_to(format!("ws://127.0.0.1:{}/chat", port)).await;
+        assert!(matches!(err, ChatConnectError::WebSocketTimeout), "{:?}", err);
+        let err = connect_to(format!("wss://localhost:{}/chat", port)).await;
+        assert!(matches!(err, ChatConnectError::TlsTimeout), "{:?}", err);
+    }
+}
@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
 
+use crate::api::chat::content::MessageContent;
+
 // Messages that can be sent over the socket to interact
 // with the Trovo chat api
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -21,6 +23,10 @@ pub enum ChatSocketMessage {
     Response {
         // Sent back in responses, used to map a request to a reply
         nonce: String,
+
+        // Present if the chat token was rejected
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
     },
 
     // A simple ping message to keep the chat socket alive
@@ -128,6 +134,138 @@ pub struct ChatMessage {
     // Different from "roles", "custom_role" contains more information.
     // However, if you just need the role names, use "roles" instead.
     pub custom_role: Option<String>,
+
+    // Id of the channel the message was sent in, taken from "channel_info" of the socket message.
+    // Not sent by Trovo inside the chat itself and absent for historic messages.
+    #[serde(skip)]
+    pub channel_id: Option<i32>,
+}
+
+impl ChatMessage {
+    // Deserialize "custom_role" json string into a list of roles.
+    // Returns an empty list if the field is absent or empty.
+    pub fn custom_roles(&self) -> Result<Vec<CustomRole>, serde_json::Error> {
+        match self.custom_role.as_deref() {
+            Some(raw) if !raw.trim().is_empty() => serde_json::from_str(raw),
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Subscription tier of the sender parsed from "sub_lv", if sender is subscribed
+    pub fn subscription_tier(&self) -> Option<SubscriptionTier> {
+        self.sub_lv.as_deref().and_then(SubscriptionTier::parse)
+    }
+
+    // Helper for checking well-known roles of the sender
+    pub fn user_roles(&self) -> Roles<'_> {
+        Roles::new(&self.roles)
+    }
+
+    // Content split into text, emote and mention segments.
+    // Use `MessageContent::parse_with_emotes` to validate emotes against the channel emote set.
+    pub fn parsed_content(&self) -> MessageContent {
+        MessageContent::parse(&self.content)
+    }
+}
+
+// A single entry of the "custom_role" json string
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CustomRole {
+    // Display name of the role, e.g. "mod" or a name given by the streamer
+    pub role_name: String,
+
+    // Numeric type of the role. Built-in roles have fixed values (e.g. 100000 for streamer),
+    // custom roles created by the streamer have their own ones.
+    #[serde(default)]
+    pub role_type: i64,
+
+    // Colour of the role name in chat, like "#FF0000". Present for custom roles only.
+    #[serde(default, alias = "color", alias = "roleColour")]
+    pub role_color: Option<String>,
+
+    // Any other fields Trovo may send along with the role
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+// Subscription level of the user in the channel
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum SubscriptionTier {
+    Tier1,
+    Tier2,
+    Tier3,
+
+    // Level which is not known yet, contains the number from "sub_L<number>"
+    Other(u8),
+}
+
+impl SubscriptionTier {
+    // Parse string like "sub_L1". Returns `None` for empty or malformed values.
+    pub fn parse(sub_lv: &str) -> Option<Self> {
+        let level = sub_lv.trim().strip_prefix("sub_L")?.parse::<u8>().ok()?;
+        match level {
+            0 => None,
+            1 => Some(Self::Tier1),
+            2 => Some(Self::Tier2),
+            3 => Some(Self::Tier3),
+            n => Some(Self::Other(n)),
+        }
+    }
+
+    // Numeric level of the tier, 1 for tier 1 and so on
+    pub fn level(&self) -> u8 {
+        match self {
+            Self::Tier1 => 1,
+            Self::Tier2 => 2,
+            Self::Tier3 => 3,
+            Self::Other(n) => *n,
+        }
+    }
+}
+
+// Role names of the message sender, as sent in "roles"
+#[derive(Debug, Clone, Copy)]
+pub struct Roles<'a> {
+    roles: &'a [String],
+}
+
+impl<'a> Roles<'a> {
+    pub fn new(roles: &'a [String]) -> Self {
+        Self { roles }
+    }
+
+    // Case-insensitive check for the role name
+    pub fn has(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r.eq_ignore_ascii_case(role))
+    }
+
+    // Moderator or super moderator of the channel
+    pub fn is_mod(&self) -> bool {
+        self.has("mod") || self.has("supermod")
+    }
+
+    // Owner of the channel
+    pub fn is_streamer(&self) -> bool {
+        self.has("streamer")
+    }
+
+    pub fn is_subscriber(&self) -> bool {
+        self.has("subscriber")
+    }
+
+    pub fn is_follower(&self) -> bool {
+        self.has("follower")
+    }
+
+    // Trovo platform admin
+    pub fn is_admin(&self) -> bool {
+        self.has("admin")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&'a String> {
+        self.roles.iter()
+    }
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone)]
@@ -187,3 +325,73 @@ pub enum ChatMessageType {
     // Unknown
     Unknown = 5014,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(extra: serde_json::Value) -> ChatMessage {
+        let mut json = serde_json::json!({
+            "type": 0,
+            "content": "hi",
+            "nick_name": "viewer",
+            "message_id": "1",
+            "send_time": 0,
+        });
+        json.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn known_tiers() {
+        assert_eq!(SubscriptionTier::parse("sub_L1"), Some(SubscriptionTier::Tier1));
+        assert_eq!(SubscriptionTier::parse(" sub_L2 "), Some(SubscriptionTier::Tier2));
+        assert_eq!(SubscriptionTier::parse("sub_L3"), Some(SubscriptionTier::Tier3));
+        assert_eq!(SubscriptionTier::Tier3.level(), 3);
+    }
+
+    #[test]
+    fn unknown_tiers() {
+        assert_eq!(SubscriptionTier::parse("sub_L7"), Some(SubscriptionTier::Other(7)));
+        assert_eq!(SubscriptionTier::Other(7).level(), 7);
+        for sub_lv in ["", "sub_L0", "sub_L", "sub_Lx", "L1", "sub_L999"] {
+            assert_eq!(SubscriptionTier::parse(sub_lv), None, "{:?}", sub_lv);
+        }
+        assert_eq!(message(serde_json::json!({})).subscription_tier(), None);
+        assert_eq!(
+            message(serde_json::json!({"sub_lv": "sub_L1"})).subscription_tier(),
+            Some(SubscriptionTier::Tier1),
+        );
+    }
+
+    #[test]
+    fn custom_role_list() {
+        let raw = r##"[{"roleName": "mod", "roleType": 100001},
+                       {"roleName": "VIP", "roleType": 7, "color": "#FF0000", "isNew": true}]"##;
+        let roles = message(serde_json::json!({"custom_role": raw})).custom_roles().unwrap();
+        assert_eq!(roles.len(), 2);
+        assert_eq!((roles[0].role_name.as_str(), roles[0].role_type, &roles[0].role_color), ("mod", 100001, &None));
+        assert_eq!(roles[1].role_color.as_deref(), Some("#FF0000"));
+        assert_eq!(roles[1].extra["isNew"], serde_json::json!(true));
+    }
+
+    #[test]
+    fn missing_or_broken_custom_roles() {
+        assert!(message(serde_json::json!({})).custom_roles().unwrap().is_empty());
+        assert!(message(serde_json::json!({"custom_role": " "})).custom_roles().unwrap().is_empty());
+        assert!(message(serde_json::json!({"custom_role": "{not json"})).custom_roles().is_err());
+    }
+
+    #[test]
+    fn well_known_roles() {
+        let msg = message(serde_json::json!({"roles": ["SuperMod", "follower"]}));
+        let roles = msg.user_roles();
+        assert!(roles.is_mod() && roles.is_follower());
+        assert!(!roles.is_streamer() && !roles.is_subscriber() && !roles.is_admin());
+
+        let roles = vec!["streamer".to_string(), "subscriber".to_string(), "admin".to_string()];
+        let roles = Roles::new(&roles);
+        assert!(roles.is_streamer() && roles.is_subscriber() && roles.is_admin() && !roles.is_mod());
+        assert_eq!(roles.iter().count(), 3);
+    }
+}
@@ -0,0 +1,58 @@
+// Helpers for tests of chat streams over `MemoryTransport`
+
+use serde_json::json;
+
+use crate::api::chat::events::ChatEvents;
+use crate::api::chat::stream::{ChatConnectOptions, ChatMessageStream};
+use crate::api::chat::structs::ChatSocketMessage;
+use crate::api::chat::transport::{MemoryServer, MemoryTransport};
+
+pub(crate) fn options(events: &ChatEvents) -> ChatConnectOptions {
+    ChatConnectOptions {
+        events: events.clone(),
+        retries: 0,
+        ..Default::default()
+    }
+}
+
+// Answer the AUTH message of the client, rejecting it if `error` is given. Returns the token.
+pub(crate) async fn answer_auth(server: &mut MemoryServer, error: Option<&str>) -> String {
+    let auth: ChatSocketMessage = server.recv_json().await.unwrap().unwrap();
+    let (nonce, data) = match auth {
+        ChatSocketMessage::Auth { nonce, data } => (nonce, data),
+        other => panic!("expected AUTH, got {:?}", other),
+    };
+    server.send_json(&ChatSocketMessage::Response { nonce, error: error.map(str::to_string) });
+    data["token"].clone()
+}
+
+// Authenticated stream and the server side of its connection
+pub(crate) async fn connect(events: &ChatEvents) -> (ChatMessageStream, MemoryServer) {
+    let (transport, mut server) = MemoryTransport::pair();
+    let connecting = tokio::spawn(ChatMessageStream::connect_with_transport(
+        transport, "chat-token".to_string(), options(events),
+    ));
+    assert_eq!(answer_auth(&mut server, None).await, "chat-token");
+    (connecting.await.unwrap().unwrap(), server)
+}
+
+// A single chat of the "chats" list, sent by user 1
+pub(crate) fn chat(id: &str, content: &str) -> serde_json::Value {
+    json!({
+        "type": 0,
+        "content": content,
+        "nick_name": "nick",
+        "message_id": id,
+        "sender_id": 1,
+        "send_time": 100,
+    })
+}
+
+// Send the chats in one CHAT message of the channel
+pub(crate) fn send_chats(server: &MemoryServer, channel_id: i32, chats: Vec<serde_json::Value>) {
+    server.send_json(&json!({
+        "type": "CHAT",
+        "channel_info": {"channel_id": channel_id.to_string()},
+        "data": {"eid": "e", "chats": chats},
+    }));
+}
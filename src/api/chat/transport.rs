@@ -0,0 +1,251 @@
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_tungstenite::{
+    client_async,
+    stream::Stream as StreamSwitcher,
+    tokio::{ConnectStream, TokioAdapter},
+    tungstenite::{self, client::IntoClientRequest, error::UrlError, Message},
+    WebSocketStream,
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use futures::{
+    channel::mpsc::{self, SendError, UnboundedReceiver, UnboundedSender},
+    prelude::*,
+    sink::{Drain, SinkMapErr},
+    stream::{BoxStream, SplitSink, SplitStream},
+};
+
+use crate::api::chat::errors::ChatConnectError;
+use crate::api::chat::record::{read_recording, replay_frames, RecordedFrame, ReplaySpeed};
+use crate::utils::config::CHAT_URL;
+
+// Connection which `ChatMessageStream` reads socket frames from and writes them to
+pub trait ChatTransport: Send + 'static {
+    type Reader: Stream<Item=Result<Message, tungstenite::Error>> + Send + Unpin + 'static;
+    type Writer: Sink<Message, Error=tungstenite::Error> + Send + Unpin + 'static;
+
+    // Open the connection. Called again for every retry after a failed attempt.
+    // Every stage of opening, like TCP connection or TLS handshake, is cut after `stage_timeout`.
+    fn connect(
+        &mut self,
+        stage_timeout: Duration,
+    ) -> impl Future<Output=Result<(Self::Writer, Self::Reader), ChatConnectError>> + Send;
+
+    // Whether there is a server on the other side, which expects the AUTH handshake and pings.
+    // Recorded sessions have no one to talk to.
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    // Whether `connect` may succeed after a failed attempt. Transports which can be connected
+    // only once report the error of their only attempt.
+    fn can_retry(&self) -> bool {
+        self.is_live()
+    }
+}
+
+// Real Trovo chat WebSocket
+#[derive(Debug, Clone)]
+pub struct WebSocketTransport {
+    pub url: String,
+}
+
+impl Default for WebSocketTransport {
+    fn default() -> Self {
+        Self::new(CHAT_URL)
+    }
+}
+
+impl WebSocketTransport {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+impl ChatTransport for WebSocketTransport {
+    type Reader = SplitStream<WebSocketStream<ConnectStream>>;
+    type Writer = SplitSink<WebSocketStream<ConnectStream>, Message>;
+
+    // TCP connection, TLS and WebSocket handshakes are timed separately,
+    // so a timeout tells which of them hangs
+    async fn connect(&mut self, stage_timeout: Duration) -> Result<(Self::Writer, Self::Reader), ChatConnectError> {
+        let request = self.url.as_str().into_client_request()?;
+        let uri = request.uri();
+        let tls = match uri.scheme_str() {
+            Some("wss") => true,
+            Some("ws") => false,
+            _ => return Err(tungstenite::Error::Url(UrlError::UnsupportedUrlScheme).into()),
+        };
+        let host = uri.host().ok_or(tungstenite::Error::Url(UrlError::NoHostName))?.to_string();
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+        let tcp = timeout(stage_timeout, TcpStream::connect((host.as_str(), port)))
+            .await
+            .map_err(|_| ChatConnectError::TcpTimeout)?
+            .map_err(tungstenite::Error::Io)?;
+        let stream: ConnectStream = if tls {
+            let domain = ServerName::try_from(host.as_str())
+                .map_err(|_| tungstenite::Error::Url(UrlError::NoHostName))?;
+            let tls = timeout(stage_timeout, tls_connector().connect(domain, tcp))
+                .await
+                .map_err(|_| ChatConnectError::TlsTimeout)?
+                .map_err(tungstenite::Error::Io)?;
+            StreamSwitcher::Tls(TokioAdapter::new(tls))
+        } else {
+            StreamSwitcher::Plain(TokioAdapter::new(tcp))
+        };
+        let (ws_stream, _) = timeout(stage_timeout, client_async(request, stream))
+            .await
+            .map_err(|_| ChatConnectError::WebSocketTimeout)??;
+        Ok(ws_stream.split())
+    }
+}
+
+// Trusts the same roots as `async_tungstenite::tokio::connect_async`
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+type MemoryReader = UnboundedReceiver<Result<Message, tungstenite::Error>>;
+type MemoryWriter = SinkMapErr<UnboundedSender<Message>, fn(SendError) -> tungstenite::Error>;
+
+fn closed_connection(_: SendError) -> tungstenite::Error {
+    tungstenite::Error::ConnectionClosed
+}
+
+// In-memory connection for tests. The other side is a `MemoryServer`.
+// Can be connected only once, so it's never retried.
+#[derive(Debug)]
+pub struct MemoryTransport {
+    channels: Option<(UnboundedSender<Message>, MemoryReader)>,
+}
+
+// Server side of a `MemoryTransport`: sees what the client sent and sends frames to it
+#[derive(Debug)]
+pub struct MemoryServer {
+    sender: UnboundedSender<Result<Message, tungstenite::Error>>,
+    receiver: UnboundedReceiver<Message>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryServer) {
+        let (client_sender, server_receiver) = mpsc::unbounded();
+        let (server_sender, client_receiver) = mpsc::unbounded();
+        let transport = MemoryTransport {
+            channels: Some((client_sender, client_receiver)),
+        };
+        let server = MemoryServer {
+            sender: server_sender,
+            receiver: server_receiver,
+        };
+        (transport, server)
+    }
+}
+
+impl ChatTransport for MemoryTransport {
+    type Reader = MemoryReader;
+    type Writer = MemoryWriter;
+
+    async fn connect(&mut self, _: Duration) -> Result<(Self::Writer, Self::Reader), ChatConnectError> {
+        let (writer, reader) = self.channels.take().ok_or(ChatConnectError::SocketClosed)?;
+        let writer: MemoryWriter = writer.sink_map_err(closed_connection);
+        Ok((writer, reader))
+    }
+
+    fn can_retry(&self) -> bool {
+        false
+    }
+}
+
+impl MemoryServer {
+    // Send a raw frame to the client. Returns `false` if the client is gone.
+    pub fn send(&self, msg: Message) -> bool {
+        self.sender.unbounded_send(Ok(msg)).is_ok()
+    }
+
+    // Serialize and send a socket message as a text frame
+    pub fn send_json<T: serde::Serialize>(&self, msg: &T) -> bool {
+        match serde_json::to_string(msg) {
+            Ok(text) => self.send(Message::Text(text)),
+            Err(_) => false,
+        }
+    }
+
+    // Make the client's socket reader fail with the error
+    pub fn fail(&self, error: tungstenite::Error) -> bool {
+        self.sender.unbounded_send(Err(error)).is_ok()
+    }
+
+    // Next frame sent by the client, `None` if the client is gone
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.next().await
+    }
+
+    // Next frame sent by the client parsed as json
+    pub async fn recv_json<T: serde::de::DeserializeOwned>(&mut self) -> Option<serde_json::Result<T>> {
+        while let Some(msg) = self.recv().await {
+            match msg {
+                Message::Text(text) => return Some(serde_json::from_str(&text)),
+                Message::Binary(bytes) => return Some(serde_json::from_slice(&bytes)),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Drop the connection, client's socket reader ends
+    pub fn close(self) {}
+}
+
+type ReplayWriter = SinkMapErr<Drain<Message>, fn(std::convert::Infallible) -> tungstenite::Error>;
+
+fn infallible(error: std::convert::Infallible) -> tungstenite::Error {
+    match error {}
+}
+
+// Frames recorded by `ChatRecorder`. Everything written is discarded.
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    frames: Vec<RecordedFrame>,
+    speed: ReplaySpeed,
+}
+
+impl ReplayTransport {
+    pub fn new(frames: Vec<RecordedFrame>, speed: ReplaySpeed) -> Self {
+        Self { frames, speed }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, speed: ReplaySpeed) -> std::io::Result<Self> {
+        Ok(Self::new(read_recording(path)?, speed))
+    }
+}
+
+impl ChatTransport for ReplayTransport {
+    type Reader = BoxStream<'static, Result<Message, tungstenite::Error>>;
+    type Writer = ReplayWriter;
+
+    async fn connect(&mut self, _: Duration) -> Result<(Self::Writer, Self::Reader), ChatConnectError> {
+        let writer: ReplayWriter = sink::drain().sink_map_err(infallible);
+        let frames = std::mem::take(&mut self.frames);
+        Ok((writer, replay_frames(frames, self.speed).boxed()))
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
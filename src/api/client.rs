@@ -1,34 +1,129 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
 use std::time::Duration;
 
+use chrono::Utc;
+use futures::StreamExt;
 use reqwest;
 use reqwest::RequestBuilder;
 use serde::de::DeserializeOwned;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{timeout_at, Instant};
 
+use crate::api::chat::broadcast::{ChatMessageBroadcast, ChatSubscriber};
+use crate::api::chat::errors::{ChatMessageStreamError, ChatSubscriberError};
 use crate::api::chat::stream::ChatMessageStream;
-use crate::api::errors::{EmptyError, InvalidResponse};
+use crate::api::chat::structs::ChatMessage;
+use crate::api::chat::transport::WebSocketTransport;
+use crate::api::errors::{ConfirmTimeout, EmptyError, InvalidResponse, MissingScopes};
 use crate::api::structs::{ChannelInfo, ChatTokenResponse, CommandResponse, DeleteResponse, MessageResponse, UserInfo, UsersResponse};
-use crate::auth::auth::update_tokens;
-use crate::utils::config::authorized_headers;
+use crate::auth::auth::{authorize_scopes, force_update_tokens, merge_scopes, update_tokens};
+use crate::auth::refresher::TokenRefresher;
+use crate::auth::store::{configured_store, MemoryTokenStore, TokenStore};
+use crate::auth::structs::Tokens;
+use crate::utils::config::{client_authorized_headers, Endpoints, SETTINGS};
 
 pub struct API {
     client: reqwest::Client,
-    access_token: String,
+    client_id: String,
+    // Shared with `TokenRefresher`, which replaces them before expiry
+    tokens: Arc<watch::Sender<Tokens>>,
+    store: Arc<dyn TokenStore>,
+    // Held while tokens are replaced, refresh tokens are single use and refreshes mustn't overlap
+    refresh_lock: Arc<Mutex<()>>,
+    endpoints: Endpoints,
 }
 
 impl API {
-    // Need 'async' for awaiting 'update_tokens'
-    pub async fn new() -> API {
+    // Need 'async' for awaiting 'update_tokens'.
+    // Fails if there are no usable tokens and OAuth wasn't completed.
+    pub async fn new() -> Result<API, Box<dyn Error>> {
+        Self::with_endpoints(Endpoints::default()).await
+    }
+
+    // Same as `new`, but talks to the given servers, e.g. to a mock one
+    pub async fn with_endpoints(endpoints: Endpoints) -> Result<API, Box<dyn Error>> {
+        Self::with_store(endpoints, configured_store()).await
+    }
+
+    // Same as `with_endpoints`, but keeps tokens in the given store instead of the configured one
+    pub async fn with_store(endpoints: Endpoints, store: Arc<dyn TokenStore>) -> Result<API, Box<dyn Error>> {
         let _client = reqwest::Client::new();
-        let tokens = update_tokens(_client).await;
+        let tokens = update_tokens(_client, &endpoints, store.as_ref()).await?;
 
+        Ok(Self {
+            client: reqwest::Client::new(),
+            client_id: SETTINGS.client_id.clone(),
+            tokens: Arc::new(watch::Sender::new(tokens)),
+            store,
+            refresh_lock: Arc::new(Mutex::new(())),
+            endpoints,
+        })
+    }
+
+    // Use already known credentials without refreshing them, e.g. ones of a mock server.
+    // Nothing is persisted, tokens live only in memory.
+    pub fn with_tokens(endpoints: Endpoints, client_id: String, tokens: Tokens) -> API {
         Self {
             client: reqwest::Client::new(),
-            access_token: tokens.access_token,
+            client_id,
+            store: Arc::new(MemoryTokenStore::with_tokens(tokens.clone())),
+            tokens: Arc::new(watch::Sender::new(tokens)),
+            refresh_lock: Arc::new(Mutex::new(())),
+            endpoints,
+        }
+    }
+
+    // Same as `with_tokens`, but without a refresh token. A 401 starts OAuth.
+    pub fn with_access_token(endpoints: Endpoints, client_id: String, access_token: String) -> API {
+        Self::with_tokens(endpoints, client_id, Tokens {
+            access_token,
+            refresh_token: String::new(),
+            expires_at: None,
+            scopes: None,
+        })
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    // Tokens currently in use
+    pub fn tokens(&self) -> Tokens {
+        self.tokens.borrow().clone()
+    }
+
+    // Fail with `MissingScopes` if the user didn't grant some of the scopes.
+    // Tokens with unknown scopes pass, the server rejects the request if a scope is missing.
+    pub fn require_scopes(&self, needed: &[&str]) -> Result<(), MissingScopes> {
+        let missing = self.tokens.borrow().missing_scopes(needed);
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(MissingScopes { missing })
         }
     }
 
+    // Log in again asking for `scopes` in addition to the granted ones
+    pub async fn authorize_scopes(&mut self, scopes: &[&str]) -> Result<(), Box<dyn Error>> {
+        let _guard = self.refresh_lock.lock().await;
+        let granted = self.tokens.borrow().scopes.clone();
+        let scopes = merge_scopes(granted.as_deref(), scopes);
+        let tokens = authorize_scopes(&self.endpoints, self.store.as_ref(), &scopes).await?;
+        self.tokens.send_replace(tokens);
+        Ok(())
+    }
+
+    // Start refreshing tokens in the background shortly before they expire.
+    // Refreshing stops when the returned handle is dropped.
+    pub fn spawn_token_refresher(&self) -> TokenRefresher {
+        TokenRefresher::spawn(
+            self.client.clone(), self.endpoints.clone(), self.tokens.clone(), self.store.clone(),
+            self.refresh_lock.clone(),
+        )
+    }
+
     // In case of 401 status code, make 5 attempts with tokens refreshing, then return error
     async fn process_request<T: DeserializeOwned>(
         &mut self, request: RequestBuilder,
@@ -41,9 +136,11 @@ impl API {
 
         for _ in 0..5 {
             // Replace 'Authorization' header with new access token
+            let used = self.tokens();
+            let access_token = used.access_token.clone();
             let updated_request = request.try_clone().unwrap()
                 .headers(
-                    authorized_headers(self.access_token.clone())
+                    client_authorized_headers(&self.client_id, &access_token)
                 );
             let response = updated_request.send().await?;
             match response.status() {
@@ -63,7 +160,7 @@ impl API {
                         break;
                     }
                     // Refresh tokens
-                    self.refresh().await;
+                    self.refresh_from(&used).await?;
                 }
                 // Any other code except 200 and 401
                 _ => {
@@ -78,14 +175,26 @@ impl API {
         result
     }
 
-    pub async fn refresh(&mut self) {
-        let tokens = update_tokens(self.client.clone()).await;
-        self.access_token = tokens.access_token;
+    pub async fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let current = self.tokens();
+        self.refresh_from(&current).await
+    }
+
+    // Refresh `used` tokens, unless the background refresher has already replaced them
+    async fn refresh_from(&mut self, used: &Tokens) -> Result<(), Box<dyn Error>> {
+        let _guard = self.refresh_lock.lock().await;
+        if self.tokens.borrow().access_token != used.access_token {
+            return Ok(());
+        }
+        let tokens = force_update_tokens(self.client.clone(), &self.endpoints, self.store.as_ref(), used).await?;
+        self.tokens.send_replace(tokens);
+        Ok(())
     }
 
     pub async fn get_user_info(&mut self) -> Result<UserInfo, Box<dyn Error>> {
+        self.require_scopes(&["user_details_self"])?;
         let request = self.client
-            .get("https://open-api.trovo.live/openplatform/getuserinfo");
+            .get(self.endpoints.api("getuserinfo"));
 
         self.process_request::<UserInfo>(request).await
     }
@@ -97,7 +206,7 @@ impl API {
         body.insert("user", nicknames);
 
         let request = self.client
-            .post("https://open-api.trovo.live/openplatform/getusers")
+            .post(self.endpoints.api("getusers"))
             .json(&body);
 
         self.process_request::<UsersResponse>(request).await
@@ -108,18 +217,18 @@ impl API {
         &mut self, channel_id: Option<i32>, username: Option<String>,
     ) -> Result<ChannelInfo, Box<dyn Error>> {
         let mut body = HashMap::new();
-        if channel_id != None {
-            body.insert("channel_id", channel_id.unwrap().to_string());
+        if let Some(channel_id) = channel_id {
+            body.insert("channel_id", channel_id.to_string());
         }
-        if username != None {
-            body.insert("username", username.unwrap());
+        if let Some(username) = username {
+            body.insert("username", username);
         }
         if body.is_empty() {
             panic!("No parameters provided");
         }
 
         let request = self.client
-            .post("https://open-api.trovo.live/openplatform/channels/id")
+            .post(self.endpoints.api("channels/id"))
             .json(&body);
 
         self.process_request::<ChannelInfo>(request).await
@@ -128,11 +237,12 @@ impl API {
     pub async fn send_my(
         &mut self, content: String,
     ) -> Result<MessageResponse, Box<dyn Error>> {
+        self.require_scopes(&["chat_send_self"])?;
         let mut body = HashMap::new();
         body.insert("content", content);
 
         let request = self.client
-            .post("https://open-api.trovo.live/openplatform/chat/send")
+            .post(self.endpoints.api("chat/send"))
             .json(&body);
 
         self.process_request::<MessageResponse>(request).await
@@ -141,29 +251,53 @@ impl API {
     pub async fn send(
         &mut self, content: String, channel_id: i32,
     ) -> Result<MessageResponse, Box<dyn Error>> {
+        self.require_scopes(&["chat_send_self"])?;
         let mut body = HashMap::new();
         body.insert("content", content);
         body.insert("channel_id", channel_id.to_string());
 
         let request = self.client
-            .post("https://open-api.trovo.live/openplatform/chat/send")
+            .post(self.endpoints.api("chat/send"))
             .json(&body);
 
         self.process_request::<MessageResponse>(request).await
     }
 
+    // Send a message and wait for its echo in chat, which contains the message id.
+    // `sender_id` is the user id of the bot (`UserInfo::user_id`, not its channel id),
+    // `chat` must be connected to the channel.
+    //
+    // Returns `ConfirmTimeout` if the echo didn't arrive within `wait`.
+    pub async fn send_and_confirm(
+        &mut self, content: String, channel_id: i32, sender_id: i32,
+        chat: &ChatMessageBroadcast, wait: Duration,
+    ) -> Result<ChatMessage, Box<dyn Error>> {
+        // Subscribe before sending to not miss a fast echo
+        let mut subscriber = chat.subscribe();
+        // Trovo send time has a resolution of seconds, allow some clock difference
+        let since = Utc::now().timestamp() - 5;
+        let deadline = Instant::now() + wait;
+
+        self.send(content.clone(), channel_id).await?;
+
+        timeout_at(deadline, wait_for_echo(&mut subscriber, &content, sender_id, since))
+            .await
+            .map_err(|_| ConfirmTimeout)?
+    }
+
     // FIXME: Doesn't work at all. Server returns 400 HTTP and 20000 API status
     pub async fn delete(
         &mut self, channel_id: i32, message_id: String, sender_id: i32,
     ) -> Result<DeleteResponse, Box<dyn Error>> {
+        self.require_scopes(&["manage_messages"])?;
         let request = self.client
             .delete(
-                format!(
-                    "https://open-api.trovo.live/openplatform/channels/{}/messages/{}/users/{}",
-                    channel_id.to_string(),
+                self.endpoints.api(&format!(
+                    "channels/{}/messages/{}/users/{}",
+                    channel_id,
                     message_id,
-                    sender_id.to_string()
-                ));
+                    sender_id
+                )));
 
         self.process_request::<DeleteResponse>(request).await
     }
@@ -173,10 +307,7 @@ impl API {
         channel_id: i32,
     ) -> Result<ChatTokenResponse, Box<dyn Error>> {
         let request = self.client
-            .get(format!(
-                "https://open-api.trovo.live/openplatform/chat/channel-token/{}",
-                channel_id
-            ));
+            .get(self.endpoints.api(&format!("chat/channel-token/{}", channel_id)));
 
         self.process_request::<ChatTokenResponse>(request).await
     }
@@ -187,8 +318,10 @@ impl API {
     ) -> Result<ChatMessageStream, Box<dyn Error>> {
         let token = self.chat_token(channel_id).await?;
 
-        let messages = ChatMessageStream::connect(
-            token.token.clone()
+        let messages = ChatMessageStream::connect_with_transport(
+            WebSocketTransport::new(self.endpoints.chat_url.clone()),
+            token.token.clone(),
+            Default::default(),
         ).await?;
         println!("Connected to chat");
         Ok(messages)
@@ -197,12 +330,13 @@ impl API {
     pub async fn command(
         &mut self, command: String, channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
+        self.require_scopes(&["manage_messages"])?;
         let mut body = HashMap::new();
         body.insert("command", command);
         body.insert("channel_id", channel_id.to_string());
 
         let request = self.client
-            .post("https://open-api.trovo.live/openplatform/channels/command")
+            .post(self.endpoints.api("channels/command"))
             .json(&body);
 
         self.process_request::<CommandResponse>(request).await
@@ -212,7 +346,7 @@ impl API {
     pub async fn mods(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("mods");
+        let command = "mods".to_string();
         self.command(command, target_channel_id).await
     }
 
@@ -220,7 +354,7 @@ impl API {
     pub async fn banned(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("banned");
+        let command = "banned".to_string();
         self.command(command, target_channel_id).await
     }
 
@@ -229,12 +363,11 @@ impl API {
     pub async fn ban(
         &mut self, username: String, duration: Duration, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command;
-        if duration.is_zero() {
-            command = format!("ban {}", username);
+        let command = if duration.is_zero() {
+            format!("ban {}", username)
         } else {
-            command = format!("ban {} {}s", username, duration.as_secs());
-        }
+            format!("ban {} {}s", username, duration.as_secs())
+        };
         self.command(command, target_channel_id).await
     }
 
@@ -265,7 +398,7 @@ impl API {
     pub async fn clear(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("clear");
+        let command = "clear".to_string();
         self.command(command, target_channel_id).await
     }
 
@@ -281,7 +414,7 @@ impl API {
     pub async fn slowoff(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("slowoff");
+        let command = "slowoff".to_string();
         self.command(command, target_channel_id).await
     }
 
@@ -290,12 +423,11 @@ impl API {
     pub async fn followers(
         &mut self, duration: Duration, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command;
-        if duration.is_zero() {
-            command = format!("followers");
+        let command = if duration.is_zero() {
+            "followers".to_string()
         } else {
-            command = format!("followers {}s", duration.as_secs());
-        }
+            format!("followers {}s", duration.as_secs())
+        };
         self.command(command, target_channel_id).await
     }
 
@@ -303,7 +435,7 @@ impl API {
     pub async fn followersoff(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("followersoff");
+        let command = "followersoff".to_string();
         self.command(command, target_channel_id).await
     }
 
@@ -319,7 +451,7 @@ impl API {
     pub async fn unhost(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("unhost");
+        let command = "unhost".to_string();
         self.command(command, target_channel_id).await
     }
 
@@ -359,7 +491,26 @@ impl API {
     pub async fn fastclip(
         &mut self, target_channel_id: i32,
     ) -> Result<CommandResponse, Box<dyn Error>> {
-        let command = format!("fastclip");
+        let command = "fastclip".to_string();
         self.command(command, target_channel_id).await
     }
-}
\ No newline at end of file
+}
+
+async fn wait_for_echo(
+    subscriber: &mut ChatSubscriber, content: &str, sender_id: i32, since: i64,
+) -> Result<ChatMessage, Box<dyn Error>> {
+    while let Some(msg) = subscriber.next().await {
+        let msg = match msg {
+            Ok(msg) => msg,
+            // Skipped messages may contain the echo, but the following ones may too
+            Err(ChatSubscriberError::Lagged(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if msg.sender_id == Some(sender_id)
+            && msg.send_time >= since
+            && msg.content.trim() == content.trim() {
+            return Ok(msg);
+        }
+    }
+    Err(ChatMessageStreamError::SocketClosed(None).into())
+}
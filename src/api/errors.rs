@@ -25,3 +25,34 @@ impl fmt::Display for EmptyError {
 
 impl error::Error for EmptyError {}
 
+
+#[derive(Debug)]
+pub struct ConfirmTimeout;
+
+impl fmt::Display for ConfirmTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sent message didn't appear in chat in time")
+    }
+}
+
+impl error::Error for ConfirmTimeout {}
+
+
+// The user didn't grant scopes the method needs. `API::authorize_scopes` asks for them.
+#[derive(Debug)]
+pub struct MissingScopes {
+    pub missing: Vec<String>,
+}
+
+impl fmt::Display for MissingScopes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Access token lacks scopes: {}. Add them to \"scopes\" in settings and restart, \
+             or call API::authorize_scopes",
+            self.missing.join(", ")
+        )
+    }
+}
+
+impl error::Error for MissingScopes {}
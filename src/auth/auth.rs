@@ -1,40 +1,188 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::str;
+use std::time::Duration;
 
 use portpicker::{pick_unused_port, Port};
 use reqwest;
 use reqwest::{RequestBuilder, Response};
+use tokio::time::{timeout_at, Instant};
 
-use crate::auth::server;
-use crate::auth::structs::RefreshResponse;
-use crate::utils::config::{headers, SCOPES, SETTINGS};
-use crate::utils::db;
+use crate::auth::errors::{OAuthError, TokenStoreError};
+use crate::auth::{headless, server};
+use crate::auth::store::TokenStore;
+use crate::api::structs::UserInfo;
+use crate::auth::structs::{LoggedOut, RefreshResponse, Tokens};
+use crate::utils::config::{client_authorized_headers, headers, Endpoints, OAuthFlow, SCOPES, SETTINGS};
+use crate::utils::utils::random_string;
 
-pub async fn update_tokens(client: reqwest::Client) -> RefreshResponse {
-    let token = db::read("config", "refresh_token".to_string());
+// How long to wait for the user to log in
+const OAUTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// Port of the redirect URI when the code is pasted and there is no free port to listen on
+const HEADLESS_REDIRECT_PORT: Port = 8080;
 
-    let tokens = {
-        match token {
+// Access tokens closer to expiry than this are refreshed
+pub const REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+// Get tokens for the API: stored ones if they are still valid, otherwise refreshed or new ones.
+// Fails if the stored tokens can't be read, e.g. the encryption key is wrong.
+pub async fn update_tokens(
+    client: reqwest::Client, endpoints: &Endpoints, store: &dyn TokenStore,
+) -> Result<Tokens, Box<dyn Error>> {
+    match store.load()? {
+        // Scopes were added to settings since the last login
+        Some(tokens) if !tokens.missing_scopes(&SETTINGS.scopes).is_empty() => {
+            println!("Stored tokens lack scopes: {}", tokens.missing_scopes(&SETTINGS.scopes).join(", "));
+            let scopes = merge_scopes(tokens.scopes.as_deref(), &SETTINGS.scopes);
+            authorize_scopes(endpoints, store, &scopes).await
+        }
+        Some(tokens) if tokens.is_valid_for(REFRESH_MARGIN) => {
+            println!("Using stored tokens");
+            Ok(tokens)
+        }
+        stored => renew_tokens(client, endpoints, store, stored).await,
+    }
+}
+
+// Refresh tokens even if they look valid, e.g. after server rejected them.
+// `current` are the tokens in use, which are newer than stored ones if the store is read-only.
+// Stored tokens are used only if `current` has no refresh token.
+pub async fn force_update_tokens(
+    client: reqwest::Client, endpoints: &Endpoints, store: &dyn TokenStore, current: &Tokens,
+) -> Result<Tokens, Box<dyn Error>> {
+    let tokens = match current.refresh_token.is_empty() {
+        true => store.load()?,
+        false => Some(current.clone()),
+    };
+    renew_tokens(client, endpoints, store, tokens).await
+}
+
+async fn renew_tokens(
+    client: reqwest::Client, endpoints: &Endpoints, store: &dyn TokenStore, stored: Option<Tokens>,
+) -> Result<Tokens, Box<dyn Error>> {
+    let tokens: Tokens = {
+        match stored {
             Some(v) => {
                 println!("Refreshing tokens");
-                let res = refresh_tokens(client, v).await.unwrap();
+                let res = refresh_tokens(client, v.refresh_token, endpoints).await?;
                 println!("Refreshed");
-                res
+                // Refreshed tokens have the same scopes
+                Tokens { scopes: v.scopes, ..res.into() }
             }
             None => {
                 println!("Refresh token not found");
-                run_oauth().await.unwrap()
+                run_oauth(endpoints, &SETTINGS.scopes).await?
             }
         }
     };
-    db::write("config", "refresh_token".to_string(), tokens.refresh_token.to_string());
+    save_tokens(store, &tokens);
+
+    Ok(tokens)
+}
 
-    return tokens;
+// Log in again requesting the given scopes, e.g. ones a feature needs in addition to granted ones
+pub async fn authorize_scopes(
+    endpoints: &Endpoints, store: &dyn TokenStore, scopes: &[String],
+) -> Result<Tokens, Box<dyn Error>> {
+    let tokens = run_oauth(endpoints, scopes).await?;
+    save_tokens(store, &tokens);
+    Ok(tokens)
+}
+
+// Granted scopes followed by the new ones from `extra`, without duplicates
+pub fn merge_scopes<S: AsRef<str>>(granted: Option<&[String]>, extra: &[S]) -> Vec<String> {
+    let mut scopes = granted.map(|granted| granted.to_vec()).unwrap_or_default();
+    for scope in extra {
+        if !scopes.iter().any(|s| s == scope.as_ref()) {
+            scopes.push(scope.as_ref().to_string());
+        }
+    }
+    scopes
+}
+
+// Tokens which couldn't be saved are still used until the bot is stopped
+pub(crate) fn save_tokens(store: &dyn TokenStore, tokens: &Tokens) {
+    match store.save(tokens) {
+        Ok(()) | Err(TokenStoreError::ReadOnly) => {}
+        Err(e) => println!("Couldn't save tokens: {}", e),
+    }
+}
+
+// Revoke stored tokens with Trovo and delete them from the store.
+// Returns `None` if there were no stored tokens.
+pub async fn logout(endpoints: &Endpoints, store: &dyn TokenStore) -> Result<Option<LoggedOut>, Box<dyn Error>> {
+    let tokens = match store.load()? {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+    let client = reqwest::Client::new();
+
+    // Expired access token can't be revoked and tell whose it is, get a fresh one
+    let mut access_token = tokens.access_token.clone();
+    if !tokens.is_valid_for(Duration::ZERO) && !tokens.refresh_token.is_empty() {
+        match refresh_tokens(client.clone(), tokens.refresh_token, endpoints).await {
+            Ok(response) => access_token = response.access_token,
+            Err(e) => println!("Couldn't refresh tokens before revoking: {}", e),
+        }
+    }
+
+    let nick_name = match get_user_info(client.clone(), &access_token, endpoints).await {
+        Ok(user) => Some(user.nick_name),
+        Err(_) => None,
+    };
+    let revoked = match revoke_token(client, &access_token, endpoints).await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Couldn't revoke tokens: {}", e);
+            false
+        }
+    };
+    let forgotten = match store.clear() {
+        Ok(()) => true,
+        Err(TokenStoreError::ReadOnly) => false,
+        Err(e) => Err(e)?,
+    };
+
+    Ok(Some(LoggedOut { nick_name, revoked, forgotten }))
+}
+
+// Invalidate the access token and the refresh token issued with it
+pub async fn revoke_token(
+    client: reqwest::Client, access_token: &str, endpoints: &Endpoints,
+) -> Result<(), Box<dyn Error>> {
+    let mut body = HashMap::new();
+    body.insert("access_token", access_token);
+
+    let request = client
+        .post(endpoints.api("revoke"))
+        .headers(headers())
+        .json(&body);
+
+    let response = request.send().await?;
+
+    match response.status() {
+        reqwest::StatusCode::OK => Ok(()),
+        _ => Err(format!("Caught an invalid response: {:?}", response))?
+    }
+}
+
+async fn get_user_info(
+    client: reqwest::Client, access_token: &str, endpoints: &Endpoints,
+) -> Result<UserInfo, Box<dyn Error>> {
+    let request = client
+        .get(endpoints.api("getuserinfo"))
+        .headers(client_authorized_headers(&SETTINGS.client_id, access_token));
+
+    let response = request.send().await?;
+
+    match response.status() {
+        reqwest::StatusCode::OK => Ok(response.json::<UserInfo>().await?),
+        _ => Err(format!("Caught an invalid response: {:?}", response))?
+    }
 }
 
 pub async fn exchange_token(
-    client: reqwest::Client, auth_code: &str, redirect_uri: String,
+    client: reqwest::Client, auth_code: &str, redirect_uri: String, endpoints: &Endpoints,
 ) -> Result<RefreshResponse, Box<dyn Error>> {
     let body = {
         let mut m = HashMap::new();
@@ -46,23 +194,23 @@ pub async fn exchange_token(
     };
 
     let request = client
-        .post("https://open-api.trovo.live/openplatform/exchangetoken")
+        .post(endpoints.api("exchangetoken"))
         .headers(headers())
         .json(&body);
 
     let response = request.send().await?;
 
-    return match response.status() {
+    match response.status() {
         reqwest::StatusCode::OK => {
             let payload = response.json::<RefreshResponse>().await?;
             Ok(payload)
         }
         _ => Err(format!("Caught an invalid response: {:?}", response))?
-    };
+    }
 }
 
-async fn refresh_tokens(
-    client: reqwest::Client, token: String,
+pub(crate) async fn refresh_tokens(
+    client: reqwest::Client, token: String, endpoints: &Endpoints,
 ) -> Result<RefreshResponse, Box<dyn Error>> {
     let body: HashMap<&str, &str> = {
         let mut m: HashMap<&str, &str> = HashMap::new();
@@ -73,34 +221,65 @@ async fn refresh_tokens(
     };
 
     let request: RequestBuilder = client
-        .post("https://open-api.trovo.live/openplatform/refreshtoken")
+        .post(endpoints.api("refreshtoken"))
         .headers(headers())
         .json(&body);
 
     let response: Response = request.send().await?;
 
-    return match response.status() {
+    match response.status() {
         reqwest::StatusCode::OK => {
             let payload = response.json::<RefreshResponse>().await?;
             Ok(payload)
         }
         _ => Err(format!("Caught an invalid response: {:?}", response))?
-    };
+    }
 }
 
-pub async fn run_oauth() -> Result<RefreshResponse, Box<dyn Error>> {
-    let port: Port = pick_unused_port().unwrap();
+// Ask the user to log in and consent to `scopes`. Returned tokens remember the scopes.
+pub async fn run_oauth(endpoints: &Endpoints, scopes: &[String]) -> Result<Tokens, Box<dyn Error>> {
+    if let Some(unknown) = scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str())) {
+        Err(format!("Unknown scope {:?}, available are: {}", unknown, SCOPES.join(", ")))?
+    }
+    let flow = SETTINGS.oauth_flow;
+    // Without a free port nothing can be received, but the redirect URI is still needed
+    let (port, flow): (Port, OAuthFlow) = match pick_unused_port() {
+        Some(port) => (port, flow),
+        None if flow == OAuthFlow::Callback => Err("No free port for OAuth callback server")?,
+        None => (HEADLESS_REDIRECT_PORT, OAuthFlow::Headless),
+    };
+    // Protects from redirects which weren't initiated by us (CSRF)
+    let state = random_string(32).await;
 
     // User must open this link and login to account of bot
     let redirect_uri: String = format!("http://localhost:{}", port);
     let auth_url: String = format!(
-        "Go to link:\nhttps://open.trovo.live/page/login.html?client_id={}&response_type=code&scope={}&redirect_uri={}",
-        SETTINGS.client_id, SCOPES.join("+"), redirect_uri
+        "Go to link:\nhttps://open.trovo.live/page/login.html?client_id={}&response_type=code&scope={}&redirect_uri={}&state={}",
+        SETTINGS.client_id, scopes.join("+"), redirect_uri, state
     );
     println!("{}", auth_url);
 
-    // Out server is blocking the main thread and waiting for redirect from Trovo login page
-    let code: String = server::oauth_server(port);
+    let code: String = wait_for_code(flow, port, &state).await?;
     // Get refresh and access token
-    return exchange_token(reqwest::Client::new(), code.as_str(), redirect_uri).await;
+    let response = exchange_token(reqwest::Client::new(), code.as_str(), redirect_uri, endpoints).await?;
+    Ok(Tokens { scopes: Some(scopes.to_vec()), ..response.into() })
+}
+
+async fn wait_for_code(flow: OAuthFlow, port: Port, state: &str) -> Result<String, OAuthError> {
+    let deadline = Instant::now() + OAUTH_TIMEOUT;
+    match flow {
+        // Our server is waiting for redirect from Trovo login page
+        OAuthFlow::Callback => server::oauth_server(port, state, OAUTH_TIMEOUT).await,
+        OAuthFlow::Headless => timeout_at(deadline, headless::read_pasted_code(state))
+            .await
+            .unwrap_or(Err(OAuthError::Timeout)),
+        // Remote machines can't get the redirect, their users paste it instead
+        OAuthFlow::Auto => {
+            let code = headless::first_code(
+                server::oauth_server(port, state, OAUTH_TIMEOUT),
+                headless::read_pasted_code(state),
+            );
+            timeout_at(deadline, code).await.unwrap_or(Err(OAuthError::Timeout))
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::ErrorKind;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::auth::errors::TokenStoreError;
+use crate::utils::config::TokenKeySource;
+
+// Prefix of encrypted values, the number is the format version
+const PREFIX: &str = "enc1:";
+const SALT_LEN: usize = 16;
+const KEY_INFO: &[u8] = b"trovo-chatbot token encryption";
+
+// Secret the token encryption keys are derived from.
+// Should be random, e.g. generated with `openssl rand -base64 32`.
+pub struct TokenKey {
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenKey(..)")
+    }
+}
+
+impl TokenKey {
+    pub fn new<S: Into<Vec<u8>>>(secret: S) -> Result<Self, TokenStoreError> {
+        let secret = secret.into();
+        if secret.is_empty() {
+            return Err(TokenStoreError::KeyMissing("encryption key is empty".to_string()));
+        }
+        Ok(Self { secret })
+    }
+
+    // Read the key from the environment variable or the file. Surrounding whitespace is ignored.
+    pub fn load(source: &TokenKeySource) -> Result<Self, TokenStoreError> {
+        let secret = match source {
+            TokenKeySource::KeyEnv(name) => std::env::var(name).map_err(|_| {
+                TokenStoreError::KeyMissing(format!("environment variable {} is not set", name))
+            })?,
+            TokenKeySource::KeyFile(path) => match fs::read_to_string(path) {
+                Ok(secret) => secret,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Err(TokenStoreError::KeyMissing(format!("key file {} doesn't exist", path)));
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        Self::new(secret.trim())
+    }
+
+    // Every value gets its own salt, so the same token is never encrypted with the same key twice.
+    // `context` is bound to the value, e.g. the field name, so values can't be swapped.
+    pub fn encrypt(&self, value: &str, context: &str) -> Result<String, TokenStoreError> {
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut salt).map_err(|_| TokenStoreError::Encryption)?;
+        rng.fill(&mut nonce).map_err(|_| TokenStoreError::Encryption)?;
+
+        let mut data = value.as_bytes().to_vec();
+        self.derive(&salt)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(context), &mut data)
+            .map_err(|_| TokenStoreError::Encryption)?;
+
+        let mut encoded = Vec::with_capacity(SALT_LEN + NONCE_LEN + data.len());
+        encoded.extend_from_slice(&salt);
+        encoded.extend_from_slice(&nonce);
+        encoded.extend_from_slice(&data);
+        Ok(format!("{}{}", PREFIX, STANDARD.encode(encoded)))
+    }
+
+    // Decrypt the value produced by `encrypt` with the same context
+    pub fn decrypt(&self, value: &str, context: &str) -> Result<String, TokenStoreError> {
+        let encoded = value.strip_prefix(PREFIX).ok_or(TokenStoreError::Decryption)?;
+        let mut data = STANDARD.decode(encoded).map_err(|_| TokenStoreError::Decryption)?;
+        if data.len() < SALT_LEN + NONCE_LEN {
+            return Err(TokenStoreError::Decryption);
+        }
+        let mut ciphertext = data.split_off(SALT_LEN + NONCE_LEN);
+        let (salt, nonce) = data.split_at(SALT_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| TokenStoreError::Decryption)?;
+
+        let plaintext = self.derive(salt)?
+            .open_in_place(nonce, Aad::from(context), &mut ciphertext)
+            .map_err(|_| TokenStoreError::Decryption)?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| TokenStoreError::Decryption)
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<LessSafeKey, TokenStoreError> {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(&self.secret);
+        let okm = prk.expand(&[KEY_INFO], &AES_256_GCM).map_err(|_| TokenStoreError::Encryption)?;
+        Ok(LessSafeKey::new(UnboundKey::from(okm)))
+    }
+}
+
+// Whether the stored value was written by `TokenKey::encrypt`
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = TokenKey::new("secret").unwrap();
+        let encrypted = key.encrypt("token", "access_token").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("token"));
+        assert_eq!(key.decrypt(&encrypted, "access_token").unwrap(), "token");
+        // Fresh salt and nonce every time
+        assert_ne!(key.encrypt("token", "access_token").unwrap(), encrypted);
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let encrypted = TokenKey::new("secret").unwrap().encrypt("token", "access_token").unwrap();
+        let other = TokenKey::new("other").unwrap();
+        assert!(matches!(other.decrypt(&encrypted, "access_token"), Err(TokenStoreError::Decryption)));
+    }
+
+    #[test]
+    fn swapped_context_fails() {
+        let key = TokenKey::new("secret").unwrap();
+        let encrypted = key.encrypt("token", "access_token").unwrap();
+        assert!(matches!(key.decrypt(&encrypted, "refresh_token"), Err(TokenStoreError::Decryption)));
+    }
+
+    #[test]
+    fn malformed_value_fails() {
+        let key = TokenKey::new("secret").unwrap();
+        assert!(matches!(key.decrypt("token", "access_token"), Err(TokenStoreError::Decryption)));
+        assert!(matches!(key.decrypt("enc1:AAAA", "access_token"), Err(TokenStoreError::Decryption)));
+        assert!(matches!(key.decrypt("enc1:%%%", "access_token"), Err(TokenStoreError::Decryption)));
+    }
+
+    #[test]
+    fn missing_key() {
+        assert!(matches!(TokenKey::new(""), Err(TokenStoreError::KeyMissing(_))));
+
+        let env = TokenKeySource::KeyEnv("TROVO_CHATBOT_TEST_MISSING_KEY".to_string());
+        assert!(matches!(TokenKey::load(&env), Err(TokenStoreError::KeyMissing(_))));
+
+        let path = std::env::temp_dir().join(format!("missing-token-key-{}", std::process::id()));
+        let file = TokenKeySource::KeyFile(path.to_string_lossy().into_owned());
+        assert!(matches!(TokenKey::load(&file), Err(TokenStoreError::KeyMissing(_))));
+    }
+
+    #[test]
+    fn key_file_is_trimmed() {
+        let path = std::env::temp_dir().join(format!("token-key-{}", std::process::id()));
+        fs::write(&path, "secret\n").unwrap();
+        let loaded = TokenKey::load(&TokenKeySource::KeyFile(path.to_string_lossy().into_owned()));
+        fs::remove_file(&path).unwrap();
+
+        let encrypted = TokenKey::new("secret").unwrap().encrypt("token", "access_token").unwrap();
+        assert_eq!(loaded.unwrap().decrypt(&encrypted, "access_token").unwrap(), "token");
+    }
+}
@@ -0,0 +1,140 @@
+use std::{error::Error, fmt::Display, io};
+
+use crate::utils::errors::DatabaseError;
+
+// Errors that can happen while waiting for the OAuth redirect
+#[derive(Debug)]
+pub enum OAuthError {
+    // Error binding the callback port or talking to the browser
+    Io(io::Error),
+
+    // Nobody completed the login before the deadline
+    Timeout,
+
+    // Trovo redirected with "?error=", e.g. the user declined access
+    Denied {
+        error: String,
+        description: Option<String>,
+    },
+
+    // Pasted redirect URL can't be used
+    InvalidRedirect(String),
+}
+
+impl From<io::Error> for OAuthError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl Display for OAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Timeout => write!(f, "login wasn't completed in time"),
+            Self::Denied { error, description: Some(description) } => {
+                write!(f, "authorization denied: {} ({})", error, description)
+            }
+            Self::Denied { error, description: None } => {
+                write!(f, "authorization denied: {}", error)
+            }
+            Self::InvalidRedirect(reason) => write!(f, "invalid redirect URL: {}", reason),
+        }
+    }
+}
+
+impl Error for OAuthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Timeout => None,
+            Self::Denied { .. } => None,
+            Self::InvalidRedirect(_) => None,
+        }
+    }
+}
+
+// Errors of loading and saving tokens with a `TokenStore`
+#[derive(Debug)]
+pub enum TokenStoreError {
+    // Error reading or writing the token file
+    Io(io::Error),
+
+    // Stored tokens aren't valid json
+    Json(serde_json::Error),
+
+    // Error of the kv database
+    Kv(kv::Error),
+    Database(DatabaseError),
+
+    // Value of an environment variable can't be used
+    InvalidEnv { name: String, reason: String },
+
+    // The store can't save tokens, e.g. environment variables
+    ReadOnly,
+
+    // Tokens are encrypted, but the key isn't available
+    KeyMissing(String),
+
+    // Stored tokens can't be decrypted: the key is wrong or the data is damaged
+    Decryption,
+
+    // Random numbers or key derivation failed
+    Encryption,
+}
+
+impl From<io::Error> for TokenStoreError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for TokenStoreError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Json(error)
+    }
+}
+
+impl From<DatabaseError> for TokenStoreError {
+    fn from(error: DatabaseError) -> Self {
+        Self::Database(error)
+    }
+}
+
+impl From<kv::Error> for TokenStoreError {
+    fn from(error: kv::Error) -> Self {
+        Self::Kv(error)
+    }
+}
+
+impl Display for TokenStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Json(e) => write!(f, "invalid stored tokens: {}", e),
+            Self::Kv(e) => write!(f, "database error: {}", e),
+            Self::Database(e) => e.fmt(f),
+            Self::InvalidEnv { name, reason } => write!(f, "invalid {}: {}", name, reason),
+            Self::ReadOnly => write!(f, "token store is read-only"),
+            Self::KeyMissing(reason) => write!(f, "token encryption key is missing: {}", reason),
+            Self::Decryption => write!(f, "can't decrypt stored tokens: the key is wrong or the data is damaged"),
+            Self::Encryption => write!(f, "can't encrypt tokens"),
+        }
+    }
+}
+
+impl Error for TokenStoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Json(e) => Some(e),
+            Self::Kv(e) => Some(e),
+            Self::Database(e) => Some(e),
+            Self::InvalidEnv { .. } => None,
+            Self::ReadOnly => None,
+            Self::KeyMissing(_) => None,
+            Self::Decryption => None,
+            Self::Encryption => None,
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::future::Future;
+use std::io::{self, BufRead};
+use std::sync::OnceLock;
+use std::thread;
+
+use tokio::select;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::auth::errors::OAuthError;
+use crate::auth::server::{parse_callback, Callback};
+
+type Lines = mpsc::UnboundedReceiver<io::Result<String>>;
+
+// Wait for the user to paste the URL they were redirected to, or just the code from it.
+// Invalid input is reported and the user can try again.
+// Can be cancelled by dropping, lines typed meanwhile are ignored by the next call.
+pub async fn read_pasted_code(state: &str) -> Result<String, OAuthError> {
+    let mut lines = stdin_lines().lock().await;
+    // Typed before the prompt, e.g. while only the callback server was waiting
+    while lines.try_recv().is_ok() {}
+    println!("Paste the URL you were redirected to (it may fail to load) or the code from it:");
+    read_code(&mut lines, state).await
+}
+
+pub(crate) async fn read_code(lines: &mut Lines, state: &str) -> Result<String, OAuthError> {
+    while let Some(line) = lines.recv().await {
+        match code_from_pasted(&line?, state) {
+            Ok(Some(code)) => return Ok(code),
+            Ok(None) => continue,
+            Err(OAuthError::InvalidRedirect(reason)) => println!("{}, try again:", reason),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stdin is closed").into())
+}
+
+// Lines of stdin read on a separate thread. Tokio stdin would keep the runtime
+// from shutting down while nobody types anything. A blocked read can't be stopped,
+// so there is one thread for the whole process and every read shares it.
+fn stdin_lines() -> &'static Mutex<Lines> {
+    static LINES: OnceLock<Mutex<Lines>> = OnceLock::new();
+    LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
+}
+
+// Code of whichever comes first: the redirect to the callback server or the pasted URL.
+// If one of them can't work, e.g. the port is taken or stdin is closed, the other one is waited for.
+pub(crate) async fn first_code(
+    server: impl Future<Output = Result<String, OAuthError>>,
+    pasted: impl Future<Output = Result<String, OAuthError>>,
+) -> Result<String, OAuthError> {
+    tokio::pin!(server, pasted);
+    let (mut server_failed, mut paste_failed) = (false, false);
+    loop {
+        select! {
+            result = &mut server, if !server_failed => match result {
+                Err(OAuthError::Io(e)) if !paste_failed => {
+                    println!("OAuth callback server failed: {}", e);
+                    server_failed = true;
+                }
+                result => return result,
+            },
+            result = &mut pasted, if !paste_failed => match result {
+                Err(OAuthError::Io(_)) if !server_failed => paste_failed = true,
+                result => return result,
+            },
+        }
+    }
+}
+
+// Code from the pasted redirect URL, query string or bare code. `None` for an empty line.
+pub(crate) fn code_from_pasted(input: &str, state: &str) -> Result<Option<String>, OAuthError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(None);
+    }
+    // Bare code can't be checked against the state, the user is trusted with it
+    if !input.contains(['?', '=', '/', '&', ' ']) {
+        return Ok(Some(input.to_string()));
+    }
+
+    // Only the query matters, the host and port of the redirect may be anything
+    let query = input.split_once('?').map(|(_, query)| query).unwrap_or(input);
+    let query = query.split('#').next().unwrap_or_default();
+    match parse_callback(&format!("/?{}", query), state) {
+        Callback::Code(code) => Ok(Some(code)),
+        Callback::Denied { error, description } => Err(OAuthError::Denied { error, description }),
+        Callback::InvalidState => Err(OAuthError::InvalidRedirect(
+            "URL is from another login attempt, use the link printed last".to_string()
+        )),
+        Callback::MissingCode => Err(OAuthError::InvalidRedirect("URL has no code".to_string())),
+        Callback::NotFound | Callback::BadRequest(_) => {
+            Err(OAuthError::InvalidRedirect("Can't parse the URL".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+    use std::time::Duration;
+
+    use tokio::time::sleep;
+
+    use super::*;
+
+    fn port_taken() -> Result<String, OAuthError> {
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "port is taken").into())
+    }
+
+    async fn later(result: Result<String, OAuthError>) -> Result<String, OAuthError> {
+        sleep(Duration::from_millis(10)).await;
+        result
+    }
+
+    #[tokio::test]
+    async fn pasted_code_without_redirect() {
+        // Nothing reaches the server of a remote machine
+        let code = first_code(pending(), later(Ok("pasted".to_string()))).await.unwrap();
+        assert_eq!(code, "pasted");
+
+        let code = first_code(async { port_taken() }, later(Ok("pasted".to_string()))).await.unwrap();
+        assert_eq!(code, "pasted");
+    }
+
+    #[tokio::test]
+    async fn redirect_without_paste() {
+        let code = first_code(later(Ok("redirected".to_string())), pending()).await.unwrap();
+        assert_eq!(code, "redirected");
+
+        // Stdin of a service is closed at once
+        let mut lines = mpsc::unbounded_channel().1;
+        let code = first_code(later(Ok("redirected".to_string())), read_code(&mut lines, "st")).await.unwrap();
+        assert_eq!(code, "redirected");
+    }
+
+    #[tokio::test]
+    async fn nothing_can_bring_code() {
+        let mut lines = mpsc::unbounded_channel().1;
+        let result = first_code(later(port_taken()), read_code(&mut lines, "st")).await;
+        assert!(matches!(result, Err(OAuthError::Io(e)) if e.kind() == io::ErrorKind::AddrInUse));
+
+        let result = first_code(later(Err(OAuthError::Timeout)), pending()).await;
+        assert!(matches!(result, Err(OAuthError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn invalid_lines_are_skipped() {
+        let (sender, mut lines) = mpsc::unbounded_channel();
+        for line in ["", "http://localhost/?code=abc&state=other", "http://localhost/?code=abc&state=st"] {
+            sender.send(Ok(line.to_string())).unwrap();
+        }
+        assert_eq!(read_code(&mut lines, "st").await.unwrap(), "abc");
+    }
+
+    #[test]
+    fn code_from_url() {
+        let code = code_from_pasted("http://localhost:1234/?code=ab%2Fc&state=st\n", "st").unwrap();
+        assert_eq!(code, Some("ab/c".to_string()));
+        let code = code_from_pasted("localhost:1234/?state=st&code=abc#", "st").unwrap();
+        assert_eq!(code, Some("abc".to_string()));
+    }
+
+    #[test]
+    fn code_from_query_or_bare() {
+        assert_eq!(code_from_pasted("code=abc&state=st", "st").unwrap(), Some("abc".to_string()));
+        assert_eq!(code_from_pasted("  abc123 ", "st").unwrap(), Some("abc123".to_string()));
+        assert_eq!(code_from_pasted("  ", "st").unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_pasted_url() {
+        assert!(matches!(
+            code_from_pasted("http://localhost/?code=abc&state=other", "st"),
+            Err(OAuthError::InvalidRedirect(_)),
+        ));
+        assert!(matches!(
+            code_from_pasted("http://localhost/?state=st", "st"),
+            Err(OAuthError::InvalidRedirect(_)),
+        ));
+        assert!(matches!(
+            code_from_pasted("http://localhost/?error=access_denied&state=st", "st"),
+            Err(OAuthError::Denied { .. }),
+        ));
+    }
+
+    #[test]
+    fn error_with_wrong_state_is_invalid() {
+        assert!(matches!(
+            code_from_pasted("http://localhost/?error=access_denied&state=other", "st"),
+            Err(OAuthError::InvalidRedirect(_)),
+        ));
+        assert!(matches!(
+            code_from_pasted("http://localhost/?error=access_denied", "st"),
+            Err(OAuthError::InvalidRedirect(_)),
+        ));
+    }
+}
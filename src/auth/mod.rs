@@ -1,4 +1,9 @@
+#[allow(clippy::module_inception)]
 pub mod auth;
+pub mod crypto;
+pub mod errors;
+pub mod headless;
+pub mod refresher;
 pub mod server;
-
-mod structs;
+pub mod store;
+pub mod structs;
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::{
+    select,
+    sync::{broadcast, watch, Mutex},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::auth::{refresh_tokens, save_tokens, REFRESH_MARGIN};
+use crate::auth::store::TokenStore;
+use crate::auth::structs::Tokens;
+use crate::utils::config::Endpoints;
+
+// How long to wait before trying again after a failed refresh
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const EVENTS_BUFFER: usize = 16;
+
+// What happened to the tokens in the background
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenEvent {
+    // New tokens are in use and saved. `expires_at` is unix time in seconds.
+    Refreshed { expires_at: Option<i64> },
+
+    // Refresh failed and will be retried. The old access token is kept while it works.
+    RefreshFailed { error: String },
+}
+
+// Handle of the task which refreshes tokens of `API` shortly before they expire.
+// The task is stopped when the handle is dropped.
+#[derive(Debug)]
+pub struct TokenRefresher {
+    cancellation_token: CancellationToken,
+    events: broadcast::Sender<TokenEvent>,
+}
+
+impl TokenRefresher {
+    pub(crate) fn spawn(
+        client: reqwest::Client, endpoints: Endpoints, tokens: Arc<watch::Sender<Tokens>>,
+        store: Arc<dyn TokenStore>, refresh_lock: Arc<Mutex<()>>,
+    ) -> Self {
+        let cancellation_token = CancellationToken::new();
+        let (events, _) = broadcast::channel(EVENTS_BUFFER);
+
+        let token = cancellation_token.clone();
+        let sender = events.clone();
+        tokio::spawn(async move {
+            loop {
+                // Tokens may also be replaced by `API::refresh` after a 401, then the schedule changes
+                let mut changed = tokens.subscribe();
+                let current = tokens.borrow().clone();
+
+                select! {
+                    _ = token.cancelled() => break,
+                    _ = changed.changed() => continue,
+                    _ = wait_for_refresh(&current) => {}
+                }
+
+                let result = select! {
+                    _ = token.cancelled() => break,
+                    res = refresh_once(&client, &endpoints, &tokens, store.as_ref(), &refresh_lock, &current) => res,
+                };
+                match result {
+                    Ok(Some(expires_at)) => {
+                        // Error only means there are no subscribers at the moment
+                        sender.send(TokenEvent::Refreshed { expires_at }).ok();
+                    }
+                    // Refreshed by someone else, wait for the new tokens to expire
+                    Ok(None) => {}
+                    Err(error) => {
+                        sender.send(TokenEvent::RefreshFailed { error }).ok();
+                        select! {
+                            _ = token.cancelled() => break,
+                            _ = sleep(RETRY_DELAY) => {}
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            cancellation_token,
+            events,
+        }
+    }
+
+    // Receive events happening from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<TokenEvent> {
+        self.events.subscribe()
+    }
+
+    // Stop refreshing. Automatically called on drop.
+    pub fn stop(&self) {
+        self.cancellation_token.cancel()
+    }
+}
+
+impl Drop for TokenRefresher {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+// Replace `used` tokens with refreshed ones and return their expiry.
+// Returns `None` without refreshing if the tokens were already replaced, e.g. by `API::refresh`:
+// the used refresh token is spent then and refreshing with it again would fail.
+async fn refresh_once(
+    client: &reqwest::Client, endpoints: &Endpoints, tokens: &watch::Sender<Tokens>, store: &dyn TokenStore,
+    refresh_lock: &Mutex<()>, used: &Tokens,
+) -> Result<Option<Option<i64>>, String> {
+    // Tokens are only replaced under the lock, so they stay the used ones until saved
+    let _guard = refresh_lock.lock().await;
+    if tokens.borrow().refresh_token != used.refresh_token {
+        return Ok(None);
+    }
+    let response = refresh_tokens(client.clone(), used.refresh_token.clone(), endpoints)
+        .await
+        .map_err(|e| e.to_string())?;
+    let new_tokens = Tokens {
+        scopes: used.scopes.clone(),
+        ..response.into()
+    };
+    save_tokens(store, &new_tokens);
+    let expires_at = new_tokens.expires_at;
+    tokens.send_replace(new_tokens);
+    Ok(Some(expires_at))
+}
+
+// Sleep until the access token is about to expire.
+// Tokens with unknown expiry or without refresh token are never refreshed proactively.
+async fn wait_for_refresh(tokens: &Tokens) {
+    match tokens.expires_in() {
+        Some(left) if !tokens.refresh_token.is_empty() => {
+            sleep(left.saturating_sub(REFRESH_MARGIN)).await
+        }
+        _ => futures::future::pending().await,
+    }
+}
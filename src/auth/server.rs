@@ -1,84 +1,370 @@
 use std::collections::HashMap;
-use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::thread::sleep;
+use std::net::SocketAddr;
+use std::str;
 use std::time::Duration;
 
+use futures::future;
 use http::Uri;
 use httparse;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout_at, Instant},
+};
 
-pub fn oauth_server(port: u16) -> String {
-    // Bind free port from range [15000, 25000]
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
+use crate::auth::errors::OAuthError;
 
-    let mut result: String = String::new();
+// Browser may open a connection in advance and send nothing, don't wait for it for too long
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Wait after a failed accept before the next one
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+// Redirect with code and state is far smaller than this
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+// What a single request to the callback server turned out to be
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Callback {
+    // Redirect with the authorization code
+    Code(String),
+
+    // Redirect with "?error=", e.g. the user declined access
+    Denied {
+        error: String,
+        description: Option<String>,
+    },
+
+    // Redirect with a state we didn't send
+    InvalidState,
+
+    // Redirect without both code and error
+    MissingCode,
+
+    // Favicon and other requests not to the root
+    NotFound,
+
+    // Not a valid HTTP request or query string
+    BadRequest(String),
+}
+
+// Wait for the redirect from Trovo login page and return the authorization code.
+// Redirects with a `state` different from the given one, errors included, are rejected.
+pub async fn oauth_server(port: u16, state: &str, wait: Duration) -> Result<String, OAuthError> {
+    let listeners = bind_loopback(port).await?;
+    let deadline = Instant::now() + wait;
 
     println!("Starting server");
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        match handle_oauth_request(stream) {
-            Ok(t) => {
-                result = t;
-                break;
+    let result = loop {
+        let (stream, _) = match timeout_at(deadline, accept(&listeners)).await {
+            Ok(Ok(accepted)) => accepted,
+            // E.g. out of file descriptors or the client gave up, may pass.
+            // The pause keeps a lasting error from spinning the loop.
+            Ok(Err(e)) => {
+                println!("Couldn't accept OAuth callback connection: {}", e);
+                sleep(ACCEPT_ERROR_PAUSE).await;
+                continue;
             }
-            Err(_) => continue
+            Err(_) => break Err(OAuthError::Timeout),
         };
+        let request_deadline = deadline.min(Instant::now() + REQUEST_TIMEOUT);
+        match timeout_at(request_deadline, handle_oauth_request(stream, state)).await {
+            Ok(Ok(Callback::Code(code))) => break Ok(code),
+            Ok(Ok(Callback::Denied { error, description })) => {
+                break Err(OAuthError::Denied { error, description });
+            }
+            // A broken request of a browser shouldn't stop us from waiting for the right one
+            Ok(Ok(_)) | Ok(Err(_)) => continue,
+            Err(_) if Instant::now() >= deadline => break Err(OAuthError::Timeout),
+            Err(_) => continue,
+        }
     };
-    println!("Stopped server [{}]", result);
-    return result;
+    println!("Stopped server");
+    result
 }
 
-fn vec_to_hashmap(v: Vec<(String, String)>) -> HashMap<String, String> {
-    let map = HashMap::from_iter(v.into_iter());
-    map
+// The redirect goes to "localhost", which may resolve to either of loopback addresses.
+// IPv6 may be disabled, then only IPv4 is listened on.
+async fn bind_loopback(port: u16) -> Result<Vec<TcpListener>, OAuthError> {
+    let mut listeners = vec![TcpListener::bind(("127.0.0.1", port)).await?];
+    if let Ok(listener) = TcpListener::bind(("::1", port)).await {
+        listeners.push(listener);
+    }
+    Ok(listeners)
 }
 
-// Parse string like "key1=value1&key2=value2&key3=value3" to [(key1, value1), (key2, value2), ...]
-fn get_params(path: &str) -> Vec<(String, String)> {
-    let mut resp: Vec<(String, String)> = vec![];
-    for i in path.split("&").collect::<Vec<&str>>() {
-        let v = i.split("=").collect::<Vec<&str>>();
-        resp.push((v[0].to_string(), v[1].to_string()));
-    };
-    resp
+// Connection to whichever listener gets one first
+async fn accept(listeners: &[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+    future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await.0
 }
 
-fn handle_oauth_request(mut stream: TcpStream) -> Result<String, Box<dyn Error>> {
-    // The part we need cannot be longer than 1024 u8-chars
-    let mut buffer = [0; 1024];
+// Decode "%XX" sequences and "+" as space
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)
+                    .and_then(|hex| str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid percent-encoding in {:?}", s))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("{:?} is not valid UTF-8 when decoded", s))
+}
 
-    stream.read(&mut buffer).unwrap();
+// Parse string like "key1=value1&key2=value2" to a map, decoding keys and values.
+// A key without "=" gets an empty value, for repeated keys the first one is kept.
+fn parse_query(query: &str) -> Result<HashMap<String, String>, String> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(key)?;
+        let value = percent_decode(value)?;
+        params.entry(key).or_insert(value);
+    }
+    Ok(params)
+}
 
+// Request target like "/?code=..." if the request head is complete, `None` if more data is needed
+fn parse_request(buffer: &[u8]) -> Result<Option<String>, String> {
     let mut headers = [httparse::EMPTY_HEADER; 64];
     let mut req = httparse::Request::new(&mut headers);
+    match req.parse(buffer) {
+        Ok(httparse::Status::Complete(_)) => match req.path {
+            Some(path) if !path.is_empty() => Ok(Some(path.to_string())),
+            _ => Err("empty request path".to_string()),
+        },
+        Ok(httparse::Status::Partial) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-    req.parse(&buffer).unwrap();
+// Decide what the request with the given target is
+pub(crate) fn parse_callback(target: &str, state: &str) -> Callback {
+    let uri = match target.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(e) => return Callback::BadRequest(e.to_string()),
+    };
+    // Browsers ask for "/favicon.ico" and alike, redirect comes to the root
+    if uri.path() != "/" {
+        return Callback::NotFound;
+    }
+    let params = match parse_query(uri.query().unwrap_or_default()) {
+        Ok(params) => params,
+        Err(e) => return Callback::BadRequest(e),
+    };
 
-    let code: String;
+    // State goes first, a forged error redirect mustn't stop the login either
+    if params.get("state").map(String::as_str) != Some(state) {
+        return Callback::InvalidState;
+    }
+    if let Some(error) = params.get("error") {
+        return Callback::Denied {
+            error: error.clone(),
+            description: params.get("error_description").cloned(),
+        };
+    }
+    match params.get("code") {
+        Some(code) if !code.is_empty() => Callback::Code(code.clone()),
+        _ => Callback::MissingCode,
+    }
+}
 
-    match req.path {
-        Some(path) => {
-            let uri = path.parse::<Uri>().unwrap();
-            let query = uri.query().unwrap();
-            let params = vec_to_hashmap(get_params(query));
-            code = params["code"].clone();
+// Read the request head, which may come in several parts
+async fn read_request(stream: &mut TcpStream) -> Result<Result<String, String>, OAuthError> {
+    let mut buffer = vec![];
+    let mut chunk = [0; 1024];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(Err("connection closed before the request was complete".to_string()));
         }
-        None => {
-            panic!("Empty path");
+        buffer.extend_from_slice(&chunk[..read]);
+        match parse_request(&buffer) {
+            Ok(Some(target)) => return Ok(Ok(target)),
+            Ok(None) if buffer.len() < MAX_REQUEST_SIZE => continue,
+            Ok(None) => return Ok(Err("request is too large".to_string())),
+            Err(e) => return Ok(Err(e)),
         }
     }
+}
 
+async fn handle_oauth_request(mut stream: TcpStream, state: &str) -> Result<Callback, OAuthError> {
+    let callback = match read_request(&mut stream).await? {
+        Ok(target) => parse_callback(&target, state),
+        Err(e) => Callback::BadRequest(e),
+    };
+
+    match &callback {
+        Callback::Code(_) => {
+            respond(&mut stream, "200 OK", &result_page(true, "Bot is authorized. You can close this page.")).await?;
+        }
+        Callback::Denied { .. } => {
+            respond(&mut stream, "200 OK", &result_page(false, "Access was not granted. You can close this page.")).await?;
+        }
+        Callback::InvalidState => {
+            println!("Ignored OAuth redirect with invalid state");
+            respond(&mut stream, "400 Bad Request", &result_page(false, "Invalid login request.")).await?;
+        }
+        Callback::MissingCode => {
+            respond(&mut stream, "400 Bad Request", &result_page(false, "Authorization code is missing.")).await?;
+        }
+        Callback::NotFound => {
+            respond(&mut stream, "404 Not Found", "").await?;
+        }
+        Callback::BadRequest(e) => {
+            println!("Ignored invalid OAuth callback request: {}", e);
+            respond(&mut stream, "400 Bad Request", "").await?;
+        }
+    }
+    Ok(callback)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), OAuthError> {
     let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-        code.len(),
-        format!("Success {}", code)
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
     );
+    stream.write_all(response.as_bytes()).await?;
+    // Let the response be sent to client before the connection is dropped
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn result_page(success: bool, message: &str) -> String {
+    let title = if success { "Success" } else { "Failure" };
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Trovo chatbot: {0}</title></head>\n\
+         <body style=\"font-family: sans-serif; text-align: center; margin-top: 20vh\">\n\
+         <h1>{0}</h1>\n<p>{1}</p>\n</body>\n</html>\n",
+        title, message
+    )
+}
 
-    // Send response
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
-    sleep(Duration::from_secs(1));  // Let the response be sent to client
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(code)
-}
\ No newline at end of file
+    #[test]
+    fn query_is_decoded() {
+        let params = parse_query("code=a%2Fb%3D&state=x+y&empty=&flag").unwrap();
+        assert_eq!(params["code"], "a/b=");
+        assert_eq!(params["state"], "x y");
+        assert_eq!(params["empty"], "");
+        assert_eq!(params["flag"], "");
+    }
+
+    #[test]
+    fn query_keeps_first_repeated_key() {
+        let params = parse_query("code=1&&code=2").unwrap();
+        assert_eq!(params["code"], "1");
+    }
+
+    #[test]
+    fn malformed_percent_encoding_is_error() {
+        assert!(parse_query("code=%zz").is_err());
+        assert!(parse_query("code=abc%2").is_err());
+        assert!(parse_query("code=%ff").is_err());
+    }
+
+    #[test]
+    fn partial_request_needs_more_data() {
+        assert_eq!(parse_request(b"GET /?code=abc&sta"), Ok(None));
+        assert_eq!(parse_request(b"GET /?code=abc HTTP/1.1\r\nHost: localhost"), Ok(None));
+        assert_eq!(
+            parse_request(b"GET /?code=abc HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Ok(Some("/?code=abc".to_string())),
+        );
+    }
+
+    #[test]
+    fn malformed_request_is_error() {
+        assert!(parse_request(b"\x00\x01\x02\r\n\r\n").is_err());
+        assert!(parse_request(b"GET  HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn callback_with_code() {
+        assert_eq!(parse_callback("/?code=a%20b&state=st", "st"), Callback::Code("a b".to_string()));
+    }
+
+    #[test]
+    fn callback_with_error() {
+        assert_eq!(
+            parse_callback("/?error=access_denied&error_description=User+declined&state=st", "st"),
+            Callback::Denied {
+                error: "access_denied".to_string(),
+                description: Some("User declined".to_string()),
+            },
+        );
+    }
+
+    #[test]
+    fn error_with_wrong_state_is_rejected() {
+        assert_eq!(parse_callback("/?error=access_denied&state=other", "st"), Callback::InvalidState);
+        assert_eq!(parse_callback("/?error=access_denied", "st"), Callback::InvalidState);
+    }
+
+    #[tokio::test]
+    async fn server_waits_after_forged_error() {
+        let port = portpicker::pick_unused_port().unwrap();
+        let server = tokio::spawn(async move { oauth_server(port, "st", Duration::from_secs(5)).await });
+        let request = |target: &'static str| async move {
+            loop {
+                if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                    let head = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await.unwrap();
+                    return response;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        assert!(request("/?error=access_denied").await.starts_with("HTTP/1.1 400"));
+        assert!(request("/?code=abc&state=st").await.starts_with("HTTP/1.1 200"));
+        assert_eq!(server.await.unwrap().unwrap(), "abc");
+    }
+
+    #[tokio::test]
+    async fn server_listens_on_both_loopbacks() {
+        let port = portpicker::pick_unused_port().unwrap();
+        let listeners = bind_loopback(port).await.unwrap();
+        let addresses: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap().ip().to_string()).collect();
+        assert_eq!(addresses[0], "127.0.0.1");
+        // Without IPv6 in the environment there is nothing more to check
+        if addresses.len() == 2 {
+            assert_eq!(addresses[1], "::1");
+            let connecting = TcpStream::connect(("::1", port));
+            let (accepted, connected) = tokio::join!(accept(&listeners), connecting);
+            assert!(accepted.unwrap().1.is_ipv6());
+            connected.unwrap();
+        }
+    }
+
+    #[test]
+    fn callback_rejections() {
+        assert_eq!(parse_callback("/?code=abc&state=other", "st"), Callback::InvalidState);
+        assert_eq!(parse_callback("/?code=abc", "st"), Callback::InvalidState);
+        assert_eq!(parse_callback("/?state=st", "st"), Callback::MissingCode);
+        assert_eq!(parse_callback("/?code&state=st", "st"), Callback::MissingCode);
+        assert_eq!(parse_callback("/", "st"), Callback::InvalidState);
+        assert_eq!(parse_callback("/favicon.ico", "st"), Callback::NotFound);
+        assert!(matches!(parse_callback("/?code=%zz&state=st", "st"), Callback::BadRequest(_)));
+    }
+}
@@ -0,0 +1,400 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+
+use crate::auth::crypto::{is_encrypted, TokenKey};
+use crate::auth::errors::TokenStoreError;
+use crate::auth::structs::Tokens;
+use crate::utils::config::{default_data_dir, TokenKeySource, TokenStoreSettings, SETTINGS};
+use crate::utils::db::{Database, DB_NAME};
+
+// Environment variables read by `EnvTokenStore`
+pub const ACCESS_TOKEN_VAR: &str = "TROVO_ACCESS_TOKEN";
+pub const REFRESH_TOKEN_VAR: &str = "TROVO_REFRESH_TOKEN";
+pub const EXPIRES_AT_VAR: &str = "TROVO_TOKEN_EXPIRES_AT";
+
+// Where tokens are kept between restarts
+pub trait TokenStore: Send + Sync {
+    // Stored tokens, `None` if there are none yet
+    fn load(&self) -> Result<Option<Tokens>, TokenStoreError>;
+
+    fn save(&self, tokens: &Tokens) -> Result<(), TokenStoreError>;
+
+    // Forget stored tokens. Clearing an empty store isn't an error.
+    fn clear(&self) -> Result<(), TokenStoreError>;
+}
+
+// Store selected by `token_store` in settings, encrypted if `token_encryption` is set
+pub fn configured_store() -> Arc<dyn TokenStore> {
+    let store = store_from_settings(&SETTINGS.token_store, &SETTINGS.data_dir);
+    match &SETTINGS.token_encryption {
+        Some(key_source) => Arc::new(EncryptedTokenStore::new(store, key_source.clone())),
+        None => store,
+    }
+}
+
+// Relative paths in `settings` are inside `data_dir`
+pub fn store_from_settings(settings: &TokenStoreSettings, data_dir: &Path) -> Arc<dyn TokenStore> {
+    match settings {
+        TokenStoreSettings::Kv { path } => Arc::new(KvTokenStore::new(data_dir.join(path))),
+        TokenStoreSettings::File { path } => Arc::new(FileTokenStore::new(data_dir.join(path))),
+        TokenStoreSettings::Env => Arc::new(EnvTokenStore),
+        TokenStoreSettings::Memory => Arc::new(MemoryTokenStore::default()),
+    }
+}
+
+// "config" bucket of the kv database, the original storage of the bot
+#[derive(Debug, Clone)]
+pub struct KvTokenStore {
+    path: PathBuf,
+}
+
+impl Default for KvTokenStore {
+    fn default() -> Self {
+        Self::new(default_data_dir().join(DB_NAME))
+    }
+}
+
+impl KvTokenStore {
+    const BUCKET: &'static str = "config";
+
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Values are plain strings, as written by the first versions of the bot
+    fn bucket(&self) -> Result<kv::Bucket<'static, String, String>, TokenStoreError> {
+        Ok(Database::open(&self.path)?.string_bucket(Self::BUCKET)?)
+    }
+}
+
+impl TokenStore for KvTokenStore {
+    // Installations which kept only the refresh token are converted by the database migrations
+    fn load(&self) -> Result<Option<Tokens>, TokenStoreError> {
+        match self.bucket()?.get(&"tokens".to_string())? {
+            Some(tokens) => Ok(Some(serde_json::from_str(&tokens)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, tokens: &Tokens) -> Result<(), TokenStoreError> {
+        let bucket = self.bucket()?;
+        bucket.set(&"tokens".to_string(), &serde_json::to_string(tokens)?)?;
+        // Kept for older versions of the bot
+        bucket.set(&"refresh_token".to_string(), &tokens.refresh_token)?;
+        bucket.flush()?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        let bucket = self.bucket()?;
+        bucket.remove(&"tokens".to_string())?;
+        bucket.remove(&"refresh_token".to_string())?;
+        bucket.flush()?;
+        Ok(())
+    }
+}
+
+// Plain json file with `Tokens`
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+}
+
+impl FileTokenStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, TokenStoreError> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => Ok(Some(serde_json::from_str(&text)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written to a temporary file first, so a crash doesn't leave a broken file.
+    // Only the owner may read it, tokens are in plain text.
+    fn save(&self, tokens: &Tokens) -> Result<(), TokenStoreError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        // A leftover of a crashed save may have other permissions, they are kept on open
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(tokens)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Tokens injected with environment variables, e.g. into a container.
+// Only the refresh token is required. Refreshed tokens live only in memory: `API` refreshes
+// with the tokens in use, the variables are read only at start.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvTokenStore;
+
+impl TokenStore for EnvTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, TokenStoreError> {
+        let refresh_token = match env_var(REFRESH_TOKEN_VAR)? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let expires_at = match env_var(EXPIRES_AT_VAR)? {
+            Some(value) => Some(value.parse::<i64>().map_err(|e| TokenStoreError::InvalidEnv {
+                name: EXPIRES_AT_VAR.to_string(),
+                reason: e.to_string(),
+            })?),
+            None => None,
+        };
+        let access_token = env_var(ACCESS_TOKEN_VAR)?;
+        Ok(Some(Tokens {
+            // Without access token its expiry means nothing, so it's refreshed at once
+            expires_at: access_token.as_ref().and(expires_at),
+            access_token: access_token.unwrap_or_default(),
+            refresh_token,
+            scopes: None,
+        }))
+    }
+
+    fn save(&self, _tokens: &Tokens) -> Result<(), TokenStoreError> {
+        Err(TokenStoreError::ReadOnly)
+    }
+
+    // Variables have to be removed where they are set
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        Err(TokenStoreError::ReadOnly)
+    }
+}
+
+// Empty and unset variables are the same
+fn env_var(name: &str) -> Result<Option<String>, TokenStoreError> {
+    match env::var(name) {
+        Ok(value) if value.trim().is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value.trim().to_string())),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(TokenStoreError::InvalidEnv {
+            name: name.to_string(),
+            reason: e.to_string(),
+        }),
+    }
+}
+
+// Tokens kept only while the process runs, e.g. for tests
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<Option<Tokens>>,
+}
+
+impl MemoryTokenStore {
+    pub fn with_tokens(tokens: Tokens) -> Self {
+        Self { tokens: Mutex::new(Some(tokens)) }
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn load(&self) -> Result<Option<Tokens>, TokenStoreError> {
+        Ok(self.tokens.lock().unwrap().clone())
+    }
+
+    fn save(&self, tokens: &Tokens) -> Result<(), TokenStoreError> {
+        *self.tokens.lock().unwrap() = Some(tokens.clone());
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        *self.tokens.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+// Encrypts tokens before they get to another store and decrypts them after loading.
+// The key is read on every access, so a missing key is reported as an error of the access.
+pub struct EncryptedTokenStore {
+    inner: Arc<dyn TokenStore>,
+    key_source: TokenKeySource,
+}
+
+impl EncryptedTokenStore {
+    pub fn new(inner: Arc<dyn TokenStore>, key_source: TokenKeySource) -> Self {
+        Self { inner, key_source }
+    }
+}
+
+impl TokenStore for EncryptedTokenStore {
+    // Plaintext tokens, e.g. stored before encryption was enabled, are returned as is
+    fn load(&self) -> Result<Option<Tokens>, TokenStoreError> {
+        let tokens = match self.inner.load()? {
+            Some(tokens) => tokens,
+            None => return Ok(None),
+        };
+        if !is_encrypted(&tokens.refresh_token) && !is_encrypted(&tokens.access_token) {
+            return Ok(Some(tokens));
+        }
+        let key = TokenKey::load(&self.key_source)?;
+        let decrypt = |value: String, context: &str| {
+            if is_encrypted(&value) { key.decrypt(&value, context) } else { Ok(value) }
+        };
+        Ok(Some(Tokens {
+            access_token: decrypt(tokens.access_token, "access_token")?,
+            refresh_token: decrypt(tokens.refresh_token, "refresh_token")?,
+            expires_at: tokens.expires_at,
+            scopes: tokens.scopes,
+        }))
+    }
+
+    fn save(&self, tokens: &Tokens) -> Result<(), TokenStoreError> {
+        let key = TokenKey::load(&self.key_source)?;
+        self.inner.save(&Tokens {
+            access_token: key.encrypt(&tokens.access_token, "access_token")?,
+            refresh_token: key.encrypt(&tokens.refresh_token, "refresh_token")?,
+            expires_at: tokens.expires_at,
+            scopes: tokens.scopes.clone(),
+        })
+    }
+    // Nothing to decrypt, so the key isn't needed
+    fn clear(&self) -> Result<(), TokenStoreError> {
+        self.inner.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(refresh_token: &str) -> Tokens {
+        Tokens {
+            access_token: "access".to_string(),
+            refresh_token: refresh_token.to_string(),
+            expires_at: Some(100),
+            scopes: Some(vec!["chat_send_self".to_string()]),
+        }
+    }
+
+    // Fresh path in the temporary directory, `name` must be unique among tests
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("trovo-chatbot-tokens-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // Save, overwrite and clear twice, the second clear finds nothing
+    fn check_round_trip(store: &dyn TokenStore) {
+        assert_eq!(store.load().unwrap(), None);
+        store.save(&tokens("first")).unwrap();
+        assert_eq!(store.load().unwrap(), Some(tokens("first")));
+        store.save(&tokens("second")).unwrap();
+        assert_eq!(store.load().unwrap(), Some(tokens("second")));
+        store.clear().unwrap();
+        assert_eq!(store.load().unwrap(), None);
+        store.clear().unwrap();
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        check_round_trip(&MemoryTokenStore::default());
+    }
+
+    #[test]
+    fn kv_round_trip() {
+        let path = temp_path("kv");
+        check_round_trip(&KvTokenStore::new(&path));
+
+        // Older versions of the bot read only the refresh token
+        let store = KvTokenStore::new(&path);
+        store.save(&tokens("shared")).unwrap();
+        let old = store.bucket().unwrap().get(&"refresh_token".to_string()).unwrap();
+        assert_eq!(old.as_deref(), Some("shared"));
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = temp_path("file");
+        let path = dir.join("nested").join("tokens.json");
+        check_round_trip(&FileTokenStore::new(&path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("file-mode");
+        // Leftover of a crashed save readable by everyone
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, "").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        FileTokenStore::new(&path).save(&tokens("private")).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn broken_file_is_an_error() {
+        let path = temp_path("file-broken");
+        fs::write(&path, "not json").unwrap();
+        let loaded = FileTokenStore::new(&path).load();
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+
+    // The only test touching the variables, so tests running in parallel don't see each other's
+    #[test]
+    fn env_is_read_only() {
+        let store = EnvTokenStore;
+        for name in [ACCESS_TOKEN_VAR, REFRESH_TOKEN_VAR, EXPIRES_AT_VAR] {
+            env::remove_var(name);
+        }
+        assert_eq!(store.load().unwrap(), None);
+
+        env::set_var(REFRESH_TOKEN_VAR, " refresh ");
+        env::set_var(EXPIRES_AT_VAR, "100");
+        // Expiry of a missing access token is ignored
+        assert_eq!(store.load().unwrap(), Some(Tokens {
+            access_token: String::new(),
+            refresh_token: "refresh".to_string(),
+            expires_at: None,
+            scopes: None,
+        }));
+        env::set_var(ACCESS_TOKEN_VAR, "access");
+        assert_eq!(store.load().unwrap().unwrap().expires_at, Some(100));
+
+        assert!(matches!(store.save(&tokens("saved")), Err(TokenStoreError::ReadOnly)));
+        assert!(matches!(store.clear(), Err(TokenStoreError::ReadOnly)));
+        assert_eq!(store.load().unwrap().unwrap().refresh_token, "refresh");
+
+        env::set_var(EXPIRES_AT_VAR, "soon");
+        assert!(matches!(store.load(), Err(TokenStoreError::InvalidEnv { .. })));
+        for name in [ACCESS_TOKEN_VAR, REFRESH_TOKEN_VAR, EXPIRES_AT_VAR] {
+            env::remove_var(name);
+        }
+    }
+}
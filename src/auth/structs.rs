@@ -1,7 +1,75 @@
+use std::time::Duration;
+
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RefreshResponse {
     pub access_token: String,
     pub refresh_token: String,
+    // Lifetime of the access token in seconds
+    #[serde(default)]
+    pub expires_in: Option<i64>,
+}
+
+// Credentials kept between restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    // Unix time in seconds when the access token expires. `None` if unknown.
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    // Scopes the user consented to. `None` for tokens stored by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+// Result of `logout`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedOut {
+    // Nickname of the account, `None` if tokens didn't work anymore
+    pub nick_name: Option<String>,
+    // Trovo invalidated the tokens. Otherwise they stay valid until they expire.
+    pub revoked: bool,
+    // Tokens were deleted from the store. Read-only stores have to be cleared by hand.
+    pub forgotten: bool,
+}
+
+impl From<RefreshResponse> for Tokens {
+    fn from(response: RefreshResponse) -> Self {
+        Self {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            expires_at: response.expires_in.map(|secs| Utc::now().timestamp() + secs),
+            scopes: None,
+        }
+    }
+}
+
+impl Tokens {
+    // Time left until the access token expires, zero if already expired
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.expires_at.map(|at| Duration::from_secs((at - Utc::now().timestamp()).max(0) as u64))
+    }
+
+    // Scopes from `needed` which weren't granted. Nothing is reported if granted scopes are unknown.
+    pub fn missing_scopes<S: AsRef<str>>(&self, needed: &[S]) -> Vec<String> {
+        match &self.scopes {
+            Some(granted) => needed.iter()
+                .map(|scope| scope.as_ref())
+                .filter(|scope| !granted.iter().any(|granted| granted == scope))
+                .map(|scope| scope.to_string())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // Whether the access token is known to stay valid for at least `margin`
+    pub fn is_valid_for(&self, margin: Duration) -> bool {
+        match self.expires_in() {
+            Some(left) => left > margin,
+            None => false,
+        }
+    }
 }
@@ -1,4 +1,7 @@
 pub mod api;
 pub mod auth;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod storage;
 pub mod utils;
 // pub mod commands;
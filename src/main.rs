@@ -1,28 +1,241 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use chrono::{Local, Utc};
 use futures::StreamExt;
+use tokio::select;
 
+use trovo_chatbot::api::chat::stream::ChatMessageStream;
 use trovo_chatbot::api::client::API;
-use trovo_chatbot::utils::config::SETTINGS;
+use trovo_chatbot::auth::auth::logout;
+use trovo_chatbot::auth::store::configured_store;
+use trovo_chatbot::storage::backup::Backup;
+use trovo_chatbot::storage::storage::{configured_storage, Storage};
+use trovo_chatbot::storage::structs::{MessageRecord, UserRecord};
+use trovo_chatbot::storage::writer::StorageWriter;
+use trovo_chatbot::utils::config::{init_settings, Endpoints, SettingsSources, SETTINGS};
+use trovo_chatbot::utils::reload::SettingsWatcher;
+
+// How often the settings file is checked for changes
+const RELOAD_PERIOD: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "Usage: trovo-chatbot [--config PATH] [--set KEY=VALUE]... [COMMAND]
+Commands:
+  run                           Run the bot, the default
+  logout                        Revoke and forget stored tokens
+  backup FILE [--tokens]        Export all bot data, with plaintext tokens if --tokens is given
+  restore FILE [--no-tokens]    Replace all bot data with the backup";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut api = API::new().await;
+    let mut sources = SettingsSources::default();
+    let mut command = None;
+    let mut command_args = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                sources.path = Some(args.next().ok_or(USAGE)?.into());
+            }
+            "-s" | "--set" => {
+                let value = args.next().ok_or(USAGE)?;
+                let (key, value) = value.split_once('=').ok_or(USAGE)?;
+                sources.overrides.push((key.to_string(), value.to_string()));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if command.is_none() => command = Some(arg),
+            _ => command_args.push(arg),
+        }
+    }
+    if let Err(e) = init_settings(&sources) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+    // Data of old versions is in the working directory, it's moved before anything is opened
+    match SETTINGS.move_legacy_data() {
+        Ok(Some(legacy)) => println!("Moved {} into {}", legacy.display(), SETTINGS.data_dir.display()),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Can't move old data into {}: {}", SETTINGS.data_dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    // Opening migrates the data, or refuses data from a newer version before anything is changed
+    let load_tokens = matches!(command.as_deref(), None | Some("run"));
+    let storage = match open_data(load_tokens) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Can't open data in {}: {}", SETTINGS.data_dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match command.as_deref() {
+        None | Some("run") if command_args.is_empty() => run(sources, storage).await,
+        Some("logout") if command_args.is_empty() => run_logout().await,
+        Some("backup") => match command_args.as_slice() {
+            [path] => run_backup(path, storage.as_ref(), false),
+            [path, flag] if flag == "--tokens" => run_backup(path, storage.as_ref(), true),
+            _ => Err(USAGE.into()),
+        },
+        Some("restore") => match command_args.as_slice() {
+            [path] => run_restore(path, storage.as_ref(), true),
+            [path, flag] if flag == "--no-tokens" => run_restore(path, storage.as_ref(), false),
+            _ => Err(USAGE.into()),
+        },
+        None | Some("run") | Some("logout") => Err(USAGE.into()),
+        Some(command) => Err(format!("Unknown command {:?}\n{}", command, USAGE).into()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// The bot checks the tokens before it starts. Other commands read tokens only if they need them,
+// restore must work even if the stored ones are broken, it replaces them.
+fn open_data(load_tokens: bool) -> Result<Arc<dyn Storage>, Box<dyn std::error::Error>> {
+    let storage = configured_storage()?;
+    if load_tokens {
+        configured_store().load()?;
+    }
+    Ok(storage)
+}
+
+// Revoke and forget stored credentials
+async fn run_logout() -> Result<(), Box<dyn std::error::Error>> {
+    let logged_out = match logout(&Endpoints::default(), configured_store().as_ref()).await? {
+        Some(logged_out) => logged_out,
+        None => {
+            println!("Not logged in");
+            return Ok(());
+        }
+    };
+    match &logged_out.nick_name {
+        Some(nick_name) => println!("Logged out of {}", nick_name),
+        None => println!("Logged out of unknown account, tokens didn't work anymore"),
+    }
+    if !logged_out.revoked {
+        println!("Tokens weren't revoked and stay valid until they expire");
+    }
+    if !logged_out.forgotten {
+        println!("Token store is read-only, remove the tokens from it by hand");
+    }
+    Ok(())
+}
+
+fn run_backup(path: &str, storage: &dyn Storage, with_tokens: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let token_store = configured_store();
+    let backup = Backup::create(storage, with_tokens.then_some(token_store.as_ref()))?;
+    backup.write(path)?;
+    print_backup_summary(&backup, backup.tokens.is_some());
+    if backup.tokens.is_some() {
+        println!("The backup contains tokens, keep it private");
+    }
+    Ok(())
+}
+
+// Restored tokens replace the ones of the current login, the bot doesn't need to login again
+fn run_restore(path: &str, storage: &dyn Storage, with_tokens: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let token_store = configured_store();
+    let backup = Backup::read(path)?;
+    backup.restore(storage, with_tokens.then_some(token_store.as_ref()))?;
+    print_backup_summary(&backup, with_tokens && backup.tokens.is_some());
+    Ok(())
+}
+
+fn print_backup_summary(backup: &Backup, tokens: bool) {
+    let data = &backup.data;
+    println!(
+        "{} users, {} messages, {} moderation actions, {} point balances, {} commands{}",
+        data.users.len(),
+        data.messages.len(),
+        data.moderation_actions.len(),
+        data.points.len(),
+        data.commands.len(),
+        if tokens { ", tokens" } else { "" },
+    );
+}
+
+// Resolve the channel by name and connect to its chat
+async fn connect_channel(api: &mut API, name: &str) -> Result<(i32, ChatMessageStream), Box<dyn std::error::Error>> {
+    let users = api.get_users(vec![name.to_string()]).await?;
+    let target_user = users.users.first().ok_or("Target channel not found")?;
+    let messages = api.chat_messages_for_channel(target_user.channel_id).await?;
+    Ok((target_user.channel_id, messages))
+}
+
+// Reply of the command added from chat. Storage may block, so it's read off the runtime.
+async fn stored_reply(storage: &Arc<dyn Storage>, command: &str) -> Option<String> {
+    let storage = storage.clone();
+    let command = command.to_string();
+    match tokio::task::spawn_blocking(move || storage.command(&command)).await {
+        Ok(Ok(command)) => command.map(|command| command.response),
+        _ => None,
+    }
+}
+
+async fn run(sources: SettingsSources, storage: Arc<dyn Storage>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut api = API::new().await?;
+    // Keep tokens fresh while the bot is running
+    let token_refresher = api.spawn_token_refresher();
+    let mut token_events = token_refresher.subscribe();
+    tokio::spawn(async move {
+        while let Ok(event) = token_events.recv().await {
+            println!("[{}] {:?}", Local::now().time(), event);
+        }
+    });
+    // Channel and commands can be changed without restart
+    let settings_watcher = SettingsWatcher::spawn(sources, RELOAD_PERIOD);
+    let mut bot_settings = settings_watcher.subscribe();
 
-    let users = api.get_users(
-        vec![SETTINGS.target_channel_name.clone()]
-    ).await?;
-    let target_user = users.users.get(0).unwrap();
-    let target_channel_id = target_user.channel_id;
     let bot_user = api.get_user_info().await?;  // me
+    let writer = StorageWriter::spawn(storage.clone())?;
 
-    let mut messages = api.chat_messages_for_channel(target_channel_id).await?;
+    let mut channel_name = bot_settings.borrow_and_update().target_channel_name.clone();
+    let (mut target_channel_id, mut messages) = connect_channel(&mut api, &channel_name).await?;
+    // Closed when the watcher stops, then the current settings stay for good
+    let mut settings_open = true;
 
-    let start_time = Utc::now().timestamp();
+    let mut start_time = Utc::now().timestamp();
     let mut skipped_messages = 0;
     let mut already_skipped = false;
 
-    while let Some(msg) = messages.next().await {
-        let msg = msg?;
+    loop {
+        let msg = select! {
+            changed = bot_settings.changed(), if settings_open => {
+                if changed.is_err() {
+                    settings_open = false;
+                    continue;
+                }
+                let new_name = bot_settings.borrow_and_update().target_channel_name.clone();
+                if new_name == channel_name {
+                    continue;
+                }
+                // The old channel is kept until the new one is connected
+                println!("Switching to channel {}", new_name);
+                match connect_channel(&mut api, &new_name).await {
+                    Ok((channel_id, stream)) => {
+                        channel_name = new_name;
+                        target_channel_id = channel_id;
+                        messages = stream;
+                        start_time = Utc::now().timestamp();
+                        skipped_messages = 0;
+                        already_skipped = false;
+                    }
+                    Err(e) => println!("Couldn't switch to channel {}, staying in {}: {}", new_name, channel_name, e),
+                }
+                continue;
+            }
+            msg = messages.next() => match msg {
+                Some(msg) => msg?,
+                None => break,
+            },
+        };
         // Trovo API returns messages which sent before program start too, ignore them
         if !already_skipped {
             if start_time > msg.send_time {
@@ -33,13 +246,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("Skipped {} messages", skipped_messages);
             }
         };
-        // Ignore messages sent by me
-        if msg.sender_id != None {
-            if msg.sender_id.unwrap() == bot_user.channel_id {
-                continue;
-            }
+        // Ignore messages sent by me. Chat has the user id of the sender, not the channel id.
+        if msg.sender_id == Some(bot_user.user_id) {
+            continue;
         }
         println!("[{}] {{{}}} {}", Local::now().time(), msg.nick_name, msg.content);
+
+        if let Some(sender_id) = msg.sender_id {
+            writer.save_user(UserRecord {
+                user_id: sender_id.into(),
+                nick_name: msg.nick_name.clone(),
+                first_seen: msg.send_time,
+                last_seen: msg.send_time,
+            });
+            // Text of messages is kept only if asked for
+            if SETTINGS.log_messages {
+                writer.save_message(MessageRecord {
+                    message_id: msg.message_id.clone(),
+                    channel_id: target_channel_id.into(),
+                    user_id: sender_id.into(),
+                    nick_name: msg.nick_name.clone(),
+                    content: msg.content.clone(),
+                    sent_at: msg.send_time,
+                });
+            }
+        }
+
+        // Commands from settings take precedence over the ones added from chat
+        let reply = match msg.content.split_whitespace().next() {
+            Some(command) => {
+                let configured = bot_settings.borrow().commands.get(command).cloned();
+                match configured {
+                    Some(reply) => Some(reply),
+                    None => stored_reply(&storage, command).await,
+                }
+            }
+            None => None,
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = api.send(reply, target_channel_id).await {
+                println!("Couldn't reply to command: {}", e);
+            }
+        }
     }
     Ok(())
 }
@@ -0,0 +1,3 @@
+pub mod server;
+#[cfg(test)]
+pub(crate) mod tests;
@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_tungstenite::tokio::accept_async;
+use async_tungstenite::tungstenite::Message;
+use chrono::Utc;
+use futures::prelude::*;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::api::chat::structs::{
+    ChannelInfo, ChatMessage, ChatMessageData, ChatMessageType, ChatSocketMessage, PongMessageData,
+};
+use crate::utils::config::Endpoints;
+
+// Requests bigger than this are rejected, nothing sent by `API` comes close
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Unique id for messages and message containers
+fn next_id() -> String {
+    format!("{}{:06}", Utc::now().timestamp_millis(), NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+// A user known to the mock server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockUser {
+    pub user_id: i32,
+    pub channel_id: i32,
+    pub username: String,
+    pub nickname: String,
+}
+
+impl MockUser {
+    pub fn new(user_id: i32, channel_id: i32, username: &str) -> Self {
+        Self {
+            user_id,
+            channel_id,
+            username: username.to_string(),
+            nickname: username.to_string(),
+        }
+    }
+}
+
+// Message sent by the bot with "chat/send"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    // `None` when sent to the bot's own channel without explicit id
+    pub channel_id: Option<i32>,
+    pub content: String,
+}
+
+// Command sent by the bot with "channels/command"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentCommand {
+    pub channel_id: i32,
+    pub command: String,
+}
+
+#[derive(Debug)]
+struct ChatSocket {
+    // Set after successful AUTH
+    channel_id: Option<i32>,
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+#[derive(Debug)]
+struct MockState {
+    bot: MockUser,
+    users: Vec<MockUser>,
+    access_token: String,
+    refresh_token: String,
+    tokens_issued: u64,
+    // Number of next authorized requests answered with 401
+    unauthorized_responses: u32,
+    sent_messages: Vec<SentMessage>,
+    commands: Vec<SentCommand>,
+    chat_tokens: HashMap<String, i32>,
+    chat_sockets: HashMap<u64, ChatSocket>,
+    next_socket_id: u64,
+    ping_gap: u64,
+    token_lifetime: i64,
+}
+
+impl MockState {
+    fn issue_tokens(&mut self) -> Value {
+        self.tokens_issued += 1;
+        self.access_token = format!("mock-access-{}", self.tokens_issued);
+        self.refresh_token = format!("mock-refresh-{}", self.tokens_issued);
+        json!({
+            "access_token": self.access_token,
+            "refresh_token": self.refresh_token,
+            "expires_in": self.token_lifetime,
+            "token_type": "OAuth",
+        })
+    }
+
+    fn find_user(&self, name: &str) -> Option<&MockUser> {
+        self.users.iter().find(|u| {
+            u.username.eq_ignore_ascii_case(name) || u.nickname.eq_ignore_ascii_case(name)
+        })
+    }
+
+    fn broadcast(&mut self, channel_id: i32, msg: &ChatSocketMessage) {
+        let text = serde_json::to_string(msg).unwrap();
+        self.chat_sockets.retain(|_, socket| {
+            if socket.channel_id != Some(channel_id) {
+                return true;
+            }
+            socket.sender.send(Message::Text(text.clone())).is_ok()
+        });
+    }
+}
+
+// Fake Trovo serving the REST API used by `API` and the chat WebSocket on localhost.
+//
+// Point the bot to it with `API::with_tokens(mock.endpoints(), ...)`.
+// Stops on drop.
+#[derive(Debug)]
+pub struct MockTrovo {
+    state: Arc<Mutex<MockState>>,
+    api_addr: SocketAddr,
+    chat_addr: SocketAddr,
+    cancellation_token: CancellationToken,
+}
+
+impl MockTrovo {
+    // Start both servers on random ports. `bot` is the user who owns the access token.
+    pub async fn start(bot: MockUser) -> io::Result<MockTrovo> {
+        let mut state = MockState {
+            bot: bot.clone(),
+            users: vec![bot],
+            access_token: String::new(),
+            refresh_token: String::new(),
+            tokens_issued: 0,
+            unauthorized_responses: 0,
+            sent_messages: vec![],
+            commands: vec![],
+            chat_tokens: HashMap::new(),
+            chat_sockets: HashMap::new(),
+            next_socket_id: 0,
+            ping_gap: 30,
+            token_lifetime: 14400,
+        };
+        state.issue_tokens();
+        let state = Arc::new(Mutex::new(state));
+        let cancellation_token = CancellationToken::new();
+
+        let api_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let chat_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let api_addr = api_listener.local_addr()?;
+        let chat_addr = chat_listener.local_addr()?;
+
+        spawn_listener(api_listener, state.clone(), cancellation_token.clone(), handle_http);
+        spawn_listener(chat_listener, state.clone(), cancellation_token.clone(), handle_chat);
+
+        Ok(MockTrovo {
+            state,
+            api_addr,
+            chat_addr,
+            cancellation_token,
+        })
+    }
+
+    pub fn endpoints(&self) -> Endpoints {
+        Endpoints {
+            api_url: format!("http://{}/openplatform", self.api_addr),
+            chat_url: format!("ws://{}/chat", self.chat_addr),
+        }
+    }
+
+    pub fn add_user(&self, user: MockUser) {
+        self.state.lock().unwrap().users.push(user);
+    }
+
+    pub fn bot(&self) -> MockUser {
+        self.state.lock().unwrap().bot.clone()
+    }
+
+    // Currently valid access token
+    pub fn access_token(&self) -> String {
+        self.state.lock().unwrap().access_token.clone()
+    }
+
+    // Currently valid refresh token
+    pub fn refresh_token(&self) -> String {
+        self.state.lock().unwrap().refresh_token.clone()
+    }
+
+    // Answer the next `count` authorized requests with 401
+    pub fn fail_next_requests(&self, count: u32) {
+        self.state.lock().unwrap().unauthorized_responses = count;
+    }
+
+    // Invalidate the current access token, the bot has to refresh it
+    pub fn expire_access_token(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.tokens_issued += 1;
+        state.access_token = format!("mock-access-{}", state.tokens_issued);
+    }
+
+    // Interval in seconds sent back in PONG messages
+    pub fn set_ping_gap(&self, gap: u64) {
+        self.state.lock().unwrap().ping_gap = gap;
+    }
+
+    // "expires_in" in seconds of the tokens issued from now on
+    pub fn set_token_lifetime(&self, seconds: i64) {
+        self.state.lock().unwrap().token_lifetime = seconds;
+    }
+
+    // Messages sent with "chat/send" so far
+    pub fn sent_messages(&self) -> Vec<SentMessage> {
+        self.state.lock().unwrap().sent_messages.clone()
+    }
+
+    // Commands sent with "channels/command" so far
+    pub fn commands(&self) -> Vec<SentCommand> {
+        self.state.lock().unwrap().commands.clone()
+    }
+
+    // Build a normal chat message sent by the user
+    pub fn chat_message(user: &MockUser, content: &str) -> ChatMessage {
+        ChatMessage {
+            type_: ChatMessageType::Normal,
+            content: content.to_string(),
+            nick_name: user.nickname.clone(),
+            avatar: None,
+            sub_lv: None,
+            medals: vec![],
+            decos: vec![],
+            roles: vec![],
+            message_id: format!("{}_{}", next_id(), user.user_id),
+            sender_id: Some(user.user_id),
+            send_time: Utc::now().timestamp(),
+            content_data: HashMap::new(),
+            custom_role: None,
+            channel_id: None,
+        }
+    }
+
+    // Send chat messages to every chat socket authenticated for the channel
+    pub fn inject_chat(&self, channel_id: i32, chats: Vec<ChatMessage>) {
+        let msg = ChatSocketMessage::Chat {
+            channel_info: Some(ChannelInfo { channel_id: channel_id.to_string() }),
+            data: ChatMessageData {
+                eid: next_id(),
+                chats,
+            },
+        };
+        self.state.lock().unwrap().broadcast(channel_id, &msg);
+    }
+
+    // Number of chat sockets authenticated for the channel
+    pub fn chat_clients(&self, channel_id: i32) -> usize {
+        self.state.lock().unwrap().chat_sockets.values()
+            .filter(|s| s.channel_id == Some(channel_id))
+            .count()
+    }
+
+    // Wait until at least `count` chat sockets are authenticated for the channel
+    pub async fn wait_for_chat_clients(&self, channel_id: i32, count: usize) {
+        while self.chat_clients(channel_id) < count {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // Abruptly drop every chat socket without a close frame
+    pub fn drop_chat_sockets(&self) {
+        self.state.lock().unwrap().chat_sockets.clear();
+    }
+
+    // Stop both servers. Automatically called on drop.
+    pub fn shutdown(&self) {
+        self.cancellation_token.cancel();
+        self.drop_chat_sockets();
+    }
+}
+
+impl Drop for MockTrovo {
+    fn drop(&mut self) {
+        self.shutdown()
+    }
+}
+
+fn spawn_listener<F, Fut>(
+    listener: TcpListener,
+    state: Arc<Mutex<MockState>>,
+    cancellation_token: CancellationToken,
+    handler: F,
+) where
+    F: Fn(TcpStream, Arc<Mutex<MockState>>, CancellationToken) -> Fut + Send + 'static,
+    Fut: Future<Output=()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            select! {
+                _ = cancellation_token.cancelled() => break,
+                accepted = listener.accept() => {
+                    if let Ok((stream, _)) = accepted {
+                        tokio::spawn(handler(stream, state.clone(), cancellation_token.clone()));
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[derive(Debug)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+// Read a single request. Returns `None` when the connection is closed between requests.
+async fn read_request(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> io::Result<Option<HttpRequest>> {
+    loop {
+        let parsed = {
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(buffer) {
+                Ok(httparse::Status::Complete(header_len)) => {
+                    let headers: HashMap<String, String> = req.headers.iter()
+                        .map(|h| (
+                            h.name.to_ascii_lowercase(),
+                            String::from_utf8_lossy(h.value).into_owned(),
+                        ))
+                        .collect();
+                    let content_length = headers.get("content-length")
+                        .and_then(|v| v.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    Some((
+                        req.method.unwrap_or_default().to_string(),
+                        req.path.unwrap_or_default().to_string(),
+                        headers,
+                        header_len,
+                        content_length,
+                    ))
+                }
+                Ok(httparse::Status::Partial) => None,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        };
+
+        if let Some((method, path, headers, header_len, content_length)) = parsed {
+            if buffer.len() >= header_len + content_length {
+                let body = buffer[header_len..header_len + content_length].to_vec();
+                buffer.drain(..header_len + content_length);
+                return Ok(Some(HttpRequest { method, path, headers, body }));
+            }
+        }
+        if buffer.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request is too big"));
+        }
+
+        let mut chunk = [0; 4096];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return if buffer.is_empty() {
+                Ok(None)
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            };
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, state: Arc<Mutex<MockState>>, _: CancellationToken) {
+    let mut buffer = vec![];
+    // Keep-alive connections are used by reqwest, serve requests until the client closes
+    while let Ok(Some(request)) = read_request(&mut stream, &mut buffer).await {
+        let (status, body) = route(&request, &state);
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            _ => "Not Found",
+        };
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status, reason, body.len(), body
+        );
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn route(request: &HttpRequest, state: &Arc<Mutex<MockState>>) -> (u16, Value) {
+    let mut state = state.lock().unwrap();
+    let path = request.path.split('?').next().unwrap_or_default();
+    let path = path.trim_start_matches("/openplatform/");
+    let body: Value = serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+
+    match path {
+        "exchangetoken" => return (200, state.issue_tokens()),
+        "revoke" => {
+            return if body["access_token"].as_str() == Some(state.access_token.as_str()) {
+                // Neither token is valid anymore, nor can be guessed by the bot
+                state.access_token = "revoked".to_string();
+                state.refresh_token = "revoked".to_string();
+                (200, json!({}))
+            } else {
+                (400, json!({"status": 11714, "error": "invalid access token"}))
+            };
+        }
+        "refreshtoken" => {
+            return if body["refresh_token"].as_str() == Some(state.refresh_token.as_str()) {
+                (200, state.issue_tokens())
+            } else {
+                (400, json!({"status": 1002, "error": "invalid refresh token"}))
+            };
+        }
+        _ => {}
+    }
+
+    let authorization = request.headers.get("authorization").cloned().unwrap_or_default();
+    if state.unauthorized_responses > 0 {
+        state.unauthorized_responses -= 1;
+        return (401, json!({"status": 11714, "error": "access token expired"}));
+    }
+    if authorization != format!("OAuth {}", state.access_token) {
+        return (401, json!({"status": 11714, "error": "invalid access token"}));
+    }
+
+    match (request.method.as_str(), path) {
+        ("GET", "getuserinfo") => {
+            let bot = &state.bot;
+            (200, json!({
+                "userId": bot.user_id.to_string(),
+                "userName": bot.username,
+                "nickName": bot.nickname,
+                "email": "",
+                "profilePic": "",
+                "info": "",
+                "channelId": bot.channel_id.to_string(),
+            }))
+        }
+        ("POST", "getusers") => {
+            let names = body["user"].as_array().cloned().unwrap_or_default();
+            let users: Vec<Value> = names.iter()
+                .filter_map(|name| state.find_user(name.as_str()?))
+                .map(user_json)
+                .collect();
+            (200, json!({ "users": users }))
+        }
+        ("POST", "channels/id") => {
+            let user = match (body["channel_id"].as_str(), body["username"].as_str()) {
+                (Some(id), _) => state.users.iter().find(|u| u.channel_id.to_string() == id),
+                (None, Some(username)) => state.find_user(username),
+                _ => None,
+            };
+            match user {
+                Some(user) => (200, channel_json(user)),
+                None => (400, json!({"status": 20000, "error": "channel not found"})),
+            }
+        }
+        ("POST", "chat/send") => {
+            let content = body["content"].as_str().unwrap_or_default().to_string();
+            let channel_id = body["channel_id"].as_str().and_then(|id| id.parse().ok());
+            state.sent_messages.push(SentMessage { channel_id, content: content.clone() });
+
+            // Echo the message to the chat like Trovo does
+            let bot = state.bot.clone();
+            let target = channel_id.unwrap_or(bot.channel_id);
+            let msg = ChatSocketMessage::Chat {
+                channel_info: Some(ChannelInfo { channel_id: target.to_string() }),
+                data: ChatMessageData {
+                    eid: next_id(),
+                    chats: vec![MockTrovo::chat_message(&bot, &content)],
+                },
+            };
+            state.broadcast(target, &msg);
+            (200, json!({}))
+        }
+        ("POST", "channels/command") => {
+            let command = body["command"].as_str().unwrap_or_default().to_string();
+            let channel_id = body["channel_id"].as_str()
+                .and_then(|id| id.parse().ok())
+                .unwrap_or(state.bot.channel_id);
+            state.commands.push(SentCommand { channel_id, command });
+            (200, json!({"is_success": true, "display_msg": ""}))
+        }
+        ("GET", path) if path.starts_with("chat/channel-token/") => {
+            match path.trim_start_matches("chat/channel-token/").parse::<i32>() {
+                Ok(channel_id) => {
+                    let token = format!("mock-chat-{}-{}", channel_id, state.chat_tokens.len());
+                    state.chat_tokens.insert(token.clone(), channel_id);
+                    (200, json!({ "token": token }))
+                }
+                Err(_) => (400, json!({"status": 20000, "error": "invalid channel id"})),
+            }
+        }
+        _ => (404, json!({"status": 404, "error": "not found"})),
+    }
+}
+
+fn user_json(user: &MockUser) -> Value {
+    json!({
+        "user_id": user.user_id.to_string(),
+        "channel_id": user.channel_id.to_string(),
+        "username": user.username,
+        "nickname": user.nickname,
+    })
+}
+
+fn channel_json(user: &MockUser) -> Value {
+    json!({
+        "is_live": false,
+        "category_id": "0",
+        "category_name": "",
+        "live_title": "",
+        "audi_type": "CHANNEL_AUDIENCE_TYPE_EVERYONE",
+        "language_code": "en",
+        "thumbnail": "",
+        "current_viewers": 0,
+        "followers": 0,
+        "streamer_info": "",
+        "profile_pic": "",
+        "channel_url": format!("https://trovo.live/{}", user.username),
+        "created_at": "0",
+        "subscriber_num": 0,
+        "username": user.username,
+        "social_links": [],
+        "started_at": "0",
+        "ended_at": "0",
+    })
+}
+
+async fn handle_chat(stream: TcpStream, state: Arc<Mutex<MockState>>, cancellation_token: CancellationToken) {
+    let ws_stream = match accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(_) => return,
+    };
+    let (mut writer, mut reader) = ws_stream.split();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let socket_id = {
+        let mut state = state.lock().unwrap();
+        state.next_socket_id += 1;
+        let id = state.next_socket_id;
+        state.chat_sockets.insert(id, ChatSocket { channel_id: None, sender });
+        id
+    };
+
+    loop {
+        select! {
+            _ = cancellation_token.cancelled() => break,
+            outgoing = receiver.recv() => match outgoing {
+                Some(msg) => {
+                    if writer.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                // Socket was dropped by `drop_chat_sockets`
+                None => return,
+            },
+            incoming = reader.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(bytes))) => String::from_utf8_lossy(&bytes).into_owned(),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = match serde_json::from_str::<ChatSocketMessage>(&text) {
+                    Ok(msg) => handle_chat_message(socket_id, msg, &state),
+                    Err(_) => None,
+                };
+                if let Some(reply) = reply {
+                    if writer.send(Message::Text(reply)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    state.lock().unwrap().chat_sockets.remove(&socket_id);
+}
+
+fn handle_chat_message(socket_id: u64, msg: ChatSocketMessage, state: &Arc<Mutex<MockState>>) -> Option<String> {
+    let mut state = state.lock().unwrap();
+    match msg {
+        ChatSocketMessage::Auth { nonce, data } => {
+            let channel_id = data.get("token").and_then(|t| state.chat_tokens.get(t)).copied();
+            match channel_id {
+                Some(channel_id) => {
+                    if let Some(socket) = state.chat_sockets.get_mut(&socket_id) {
+                        socket.channel_id = Some(channel_id);
+                    }
+                    Some(json!({"type": "RESPONSE", "nonce": nonce}).to_string())
+                }
+                None => Some(json!({
+                    "type": "RESPONSE",
+                    "nonce": nonce,
+                    "error": "invalid token",
+                }).to_string()),
+            }
+        }
+        ChatSocketMessage::Ping { nonce } => {
+            let pong = ChatSocketMessage::Pong {
+                nonce,
+                data: PongMessageData { gap: state.ping_gap },
+            };
+            serde_json::to_string(&pong).ok()
+        }
+        _ => None,
+    }
+}
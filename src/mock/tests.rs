@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::StreamExt;
+use tokio::time::timeout;
+
+use crate::api::client::API;
+use crate::api::errors::ConfirmTimeout;
+use crate::auth::refresher::TokenEvent;
+use crate::auth::structs::Tokens;
+use crate::mock::server::{MockTrovo, MockUser, SentMessage};
+use crate::utils::config::{init_settings, SettingsSources};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+// Token refresh reads the client secret from settings
+pub(crate) fn init_test_settings() {
+    let overrides = [
+        ("client_id", "mock-client"),
+        ("client_secret", "mock-secret"),
+        ("target_channel_name", "viewer"),
+        ("token_store.type", "memory"),
+    ];
+    let sources = SettingsSources {
+        path: None,
+        overrides: overrides.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+    };
+    // Settings are global, another test may have loaded them already
+    init_settings(&sources).ok();
+}
+
+async fn start() -> (MockTrovo, API, MockUser) {
+    init_test_settings();
+    let mock = MockTrovo::start(MockUser::new(1, 100, "bot")).await.unwrap();
+    let viewer = MockUser::new(2, 200, "viewer");
+    mock.add_user(viewer.clone());
+    let api = API::with_tokens(mock.endpoints(), "mock-client".to_string(), Tokens {
+        access_token: mock.access_token(),
+        refresh_token: mock.refresh_token(),
+        expires_at: None,
+        scopes: None,
+    });
+    (mock, api, viewer)
+}
+
+// Tokens of the mock which expire within the refresh margin, so they are refreshed right away
+fn expiring_api(mock: &MockTrovo) -> API {
+    API::with_tokens(mock.endpoints(), "mock-client".to_string(), Tokens {
+        access_token: mock.access_token(),
+        refresh_token: mock.refresh_token(),
+        expires_at: Some(Utc::now().timestamp() + 60),
+        scopes: None,
+    })
+}
+
+#[tokio::test]
+async fn replies_to_injected_chat() {
+    let (mock, mut api, viewer) = start().await;
+    let mut chat = api.chat_messages_for_channel(viewer.channel_id).await.unwrap();
+    mock.wait_for_chat_clients(viewer.channel_id, 1).await;
+
+    mock.inject_chat(viewer.channel_id, vec![MockTrovo::chat_message(&viewer, "!ping")]);
+    let msg = timeout(WAIT, chat.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(msg.content, "!ping");
+    assert_eq!(msg.channel_id, Some(viewer.channel_id));
+
+    api.send("pong".to_string(), viewer.channel_id).await.unwrap();
+    assert_eq!(mock.sent_messages(), vec![SentMessage {
+        channel_id: Some(viewer.channel_id),
+        content: "pong".to_string(),
+    }]);
+}
+
+#[tokio::test]
+async fn refreshes_tokens_after_401() {
+    let (mock, mut api, viewer) = start().await;
+    let refresh_token = mock.refresh_token();
+    mock.expire_access_token();
+
+    api.send("after refresh".to_string(), viewer.channel_id).await.unwrap();
+    assert_eq!(mock.sent_messages().len(), 1);
+    assert_eq!(api.tokens().access_token, mock.access_token());
+    assert_ne!(mock.refresh_token(), refresh_token);
+
+    // The rotated refresh token is used for the next refresh
+    mock.fail_next_requests(1);
+    api.send("after second refresh".to_string(), viewer.channel_id).await.unwrap();
+    assert_eq!(mock.sent_messages().len(), 2);
+}
+
+#[tokio::test]
+async fn dropped_socket_ends_chat() {
+    let (mock, mut api, viewer) = start().await;
+    let mut chat = api.chat_messages_for_channel(viewer.channel_id).await.unwrap();
+    mock.wait_for_chat_clients(viewer.channel_id, 1).await;
+
+    mock.drop_chat_sockets();
+    match timeout(WAIT, chat.next()).await.unwrap() {
+        None | Some(Err(_)) => {}
+        Some(Ok(msg)) => panic!("unexpected message {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn send_is_confirmed_by_echo() {
+    let (mock, mut api, viewer) = start().await;
+    let chat = api.chat_messages_for_channel(viewer.channel_id).await.unwrap().into_broadcast();
+    mock.wait_for_chat_clients(viewer.channel_id, 1).await;
+    let bot = api.get_user_info().await.unwrap();
+    // Ids differ in the mock, so a mix-up is noticed
+    assert_ne!(bot.user_id, bot.channel_id);
+
+    let echo = api.send_and_confirm("hello".to_string(), viewer.channel_id, bot.user_id, &chat, WAIT)
+        .await
+        .unwrap();
+    assert_eq!((echo.content.as_str(), echo.sender_id), ("hello", Some(bot.user_id)));
+
+    let wrong_sender = api.send_and_confirm(
+        "hello again".to_string(), viewer.channel_id, bot.channel_id, &chat, Duration::from_millis(200),
+    ).await;
+    assert!(wrong_sender.unwrap_err().is::<ConfirmTimeout>());
+}
+
+#[tokio::test]
+async fn refresher_replaces_expiring_tokens() {
+    let (mock, _, _) = start().await;
+    let api = expiring_api(&mock);
+    let refresher = api.spawn_token_refresher();
+    let mut events = refresher.subscribe();
+
+    let event = timeout(WAIT, events.recv()).await.unwrap().unwrap();
+    let tokens = api.tokens();
+    assert_eq!(event, TokenEvent::Refreshed { expires_at: tokens.expires_at });
+    assert_eq!((tokens.access_token, tokens.refresh_token), (mock.access_token(), mock.refresh_token()));
+    // New tokens are far from expiry, nothing happens until then
+    assert!(timeout(Duration::from_millis(200), events.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn refresher_and_401_refresh_do_not_overlap() {
+    let (mock, _, _) = start().await;
+    let mut api = expiring_api(&mock);
+    let refresher = api.spawn_token_refresher();
+    let mut events = refresher.subscribe();
+
+    // The refresher wants to refresh the same tokens at the same time, it has to skip them
+    api.refresh().await.unwrap();
+    assert!(timeout(Duration::from_millis(500), events.recv()).await.is_err());
+    let tokens = api.tokens();
+    assert_eq!((tokens.access_token, tokens.refresh_token), (mock.access_token(), mock.refresh_token()));
+}
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::store::TokenStore;
+use crate::auth::structs::Tokens;
+use crate::storage::errors::BackupError;
+use crate::storage::storage::Storage;
+use crate::storage::structs::StorageData;
+
+// Marks the json as a backup of the bot, so other json files aren't restored by mistake
+pub const BACKUP_FORMAT: &str = "trovo-chatbot-backup";
+
+// Version of the archive layout written by this build. Bump it on incompatible changes.
+pub const BACKUP_VERSION: u32 = 1;
+
+// All bot data in one json archive, for moving the bot between machines or restoring the data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub format: String,
+    pub version: u32,
+    // Unix time in seconds
+    pub created_at: i64,
+    // Plaintext even if `token_encryption` is set, keep backups with tokens private
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Tokens>,
+    pub data: StorageData,
+}
+
+impl Backup {
+    // Backup of everything in `storage`, with tokens from `token_store` if it's given
+    pub fn create(storage: &dyn Storage, token_store: Option<&dyn TokenStore>) -> Result<Backup, BackupError> {
+        let tokens = match token_store {
+            Some(store) => store.load()?,
+            None => None,
+        };
+        Ok(Backup {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created_at: Utc::now().timestamp(),
+            tokens,
+            data: storage.export()?,
+        })
+    }
+
+    // Written to a temporary file first, so a crash doesn't leave a broken archive.
+    // On Unix only the owner can read the file, it may have tokens and has chat history.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), BackupError> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        // A leftover of a crashed write may have other permissions, they are kept on open
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // Format and version are checked before the rest, so archives of newer builds
+    // are reported as such instead of as invalid json
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Backup, BackupError> {
+        let value: serde_json::Value = serde_json::from_slice(&fs::read(path)?)?;
+        if value.get("format").and_then(|format| format.as_str()) != Some(BACKUP_FORMAT) {
+            return Err(BackupError::NotBackup);
+        }
+        let found = value.get("version").and_then(|version| version.as_u64()).ok_or(BackupError::NotBackup)?;
+        if found > BACKUP_VERSION as u64 {
+            return Err(BackupError::UnsupportedVersion { found, supported: BACKUP_VERSION });
+        }
+        let backup: Backup = serde_json::from_value(value)?;
+        backup.validate()?;
+        Ok(backup)
+    }
+
+    // Check everything before the restore changes anything. Records with the same key
+    // would silently replace each other in storage.
+    pub fn validate(&self) -> Result<(), BackupError> {
+        if self.format != BACKUP_FORMAT {
+            return Err(BackupError::NotBackup);
+        }
+        if self.version > BACKUP_VERSION {
+            return Err(BackupError::UnsupportedVersion { found: self.version as u64, supported: BACKUP_VERSION });
+        }
+        let data = &self.data;
+        unique("user", data.users.iter().map(|user| user.user_id))?;
+        unique("message", data.messages.iter().map(|message| &message.message_id))?;
+        unique("point balance of user", data.points.iter().map(|points| points.user_id))?;
+        unique("command", data.commands.iter().map(|command| &command.name))?;
+        Ok(())
+    }
+
+    // Replace the data in `storage` with the backup. Tokens are saved to `token_store`
+    // if both the backup has them and the store is given, only after the data is imported.
+    // A failed restore leaves the tokens as they were.
+    pub fn restore(&self, storage: &dyn Storage, token_store: Option<&dyn TokenStore>) -> Result<(), BackupError> {
+        self.validate()?;
+        storage.import(&self.data)?;
+        if let (Some(tokens), Some(store)) = (&self.tokens, token_store) {
+            store.save(tokens)?;
+        }
+        Ok(())
+    }
+}
+
+fn unique<K: Display>(kind: &str, keys: impl Iterator<Item=K>) -> Result<(), BackupError> {
+    let mut seen = HashSet::new();
+    for key in keys {
+        if !seen.insert(key.to_string()) {
+            return Err(BackupError::Invalid(format!("{} {} is there twice", kind, key)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::auth::store::MemoryTokenStore;
+    use crate::storage::sqlite::SqliteStorage;
+    use crate::storage::structs::UserRecord;
+    use crate::storage::tests::{sample_data, temp_kv};
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("trovo-chatbot-backup-{}-{}.json", name, std::process::id()))
+    }
+
+    fn tokens(refresh_token: &str) -> Tokens {
+        Tokens {
+            access_token: "access".to_string(),
+            refresh_token: refresh_token.to_string(),
+            expires_at: Some(100),
+            scopes: None,
+        }
+    }
+
+    // Kv and SQLite storages with the sample data, `name` must be unique among tests
+    fn filled_storages(name: &str) -> Vec<Arc<dyn Storage>> {
+        let storages: Vec<Arc<dyn Storage>> = vec![
+            Arc::new(temp_kv(&format!("backup-{}", name))),
+            Arc::new(SqliteStorage::in_memory().unwrap()),
+        ];
+        for storage in &storages {
+            storage.import(&sample_data()).unwrap();
+        }
+        storages
+    }
+
+    #[test]
+    fn round_trip() {
+        for (index, source) in filled_storages("source").into_iter().enumerate() {
+            let path = temp_file(&format!("round-trip-{}", index));
+            let source_tokens = MemoryTokenStore::with_tokens(tokens("backed-up"));
+            Backup::create(source.as_ref(), Some(&source_tokens)).unwrap().write(&path).unwrap();
+            let backup = Backup::read(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            // Restore to both backends, whichever the backup was made of
+            for target in [
+                Arc::new(temp_kv(&format!("backup-target-{}", index))) as Arc<dyn Storage>,
+                Arc::new(SqliteStorage::in_memory().unwrap()),
+            ] {
+                let target_tokens = MemoryTokenStore::with_tokens(tokens("current"));
+                backup.restore(target.as_ref(), Some(&target_tokens)).unwrap();
+                assert_eq!(target.export().unwrap(), sample_data());
+                assert_eq!(target_tokens.load().unwrap(), Some(tokens("backed-up")));
+            }
+        }
+    }
+
+    #[test]
+    fn without_tokens() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        let store = MemoryTokenStore::with_tokens(tokens("current"));
+        let backup = Backup::create(&storage, None).unwrap();
+        assert_eq!(backup.tokens, None);
+
+        // `--no-tokens` passes no store, even if the backup has tokens
+        let backup = Backup { tokens: Some(tokens("backed-up")), ..backup };
+        backup.restore(&storage, None).unwrap();
+        assert_eq!(store.load().unwrap(), Some(tokens("current")));
+    }
+
+    #[test]
+    fn other_json_is_not_backup() {
+        let path = temp_file("other");
+        for json in [r#"{"format": "other", "version": 1}"#, r#"{"data": {}}"#, "[]", "1"] {
+            fs::write(&path, json).unwrap();
+            assert!(matches!(Backup::read(&path), Err(BackupError::NotBackup)), "{}", json);
+        }
+        fs::write(&path, "not json").unwrap();
+        assert!(matches!(Backup::read(&path), Err(BackupError::Json(_))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let path = temp_file("newer");
+        // The layout of newer versions is unknown, only format and version are read
+        let json = serde_json::json!({"format": BACKUP_FORMAT, "version": BACKUP_VERSION + 1, "data": []});
+        fs::write(&path, json.to_string()).unwrap();
+        let result = Backup::read(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(BackupError::UnsupportedVersion { found, supported })
+                if found == BACKUP_VERSION as u64 + 1 && supported == BACKUP_VERSION
+        ));
+    }
+
+    #[test]
+    fn invalid_backup_changes_nothing() {
+        for storage in filled_storages("invalid") {
+            let store = MemoryTokenStore::with_tokens(tokens("current"));
+            let mut backup = Backup::create(storage.as_ref(), None).unwrap();
+            backup.tokens = Some(tokens("backed-up"));
+            backup.data.users.push(UserRecord { nick_name: "twin".to_string(), ..backup.data.users[0].clone() });
+
+            assert!(matches!(backup.restore(storage.as_ref(), Some(&store)), Err(BackupError::Invalid(_))));
+            assert_eq!(storage.export().unwrap(), sample_data());
+            assert_eq!(store.load().unwrap(), Some(tokens("current")));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_file("private");
+        let backup = Backup::create(&SqliteStorage::in_memory().unwrap(), None).unwrap();
+        backup.write(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{error::Error, fmt::Display, io};
+
+use crate::auth::errors::TokenStoreError;
+use crate::utils::errors::DatabaseError;
+
+// Errors of `Storage` backends
+#[derive(Debug)]
+pub enum StorageError {
+    // Error of the kv backend
+    Database(DatabaseError),
+
+    // Error of the SQLite backend
+    Sqlite(rusqlite::Error),
+
+    // Directory of the database can't be created
+    Io(io::Error),
+
+    // Schema migration to `version` failed, the database is left at the previous version
+    Migration { version: u32, error: rusqlite::Error },
+
+    // SQLite database was written by a newer version of the bot
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+impl From<DatabaseError> for StorageError {
+    fn from(error: DatabaseError) -> Self {
+        Self::Database(error)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::Sqlite(error)
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(e) => e.fmt(f),
+            Self::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            Self::Io(e) => write!(f, "can't create database directory: {}", e),
+            Self::Migration { version, error } => {
+                write!(f, "can't migrate database to version {}: {}", version, error)
+            }
+            Self::UnsupportedVersion { found, supported } => write!(
+                f, "data format version {} is newer than supported {}, update the bot", found, supported
+            ),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            Self::Sqlite(e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::Migration { error, .. } => Some(error),
+            Self::UnsupportedVersion { .. } => None,
+        }
+    }
+}
+
+// Errors of writing, reading and restoring a `Backup`
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+
+    Json(serde_json::Error),
+
+    Storage(StorageError),
+
+    TokenStore(TokenStoreError),
+
+    // File is json, but not a backup of the bot
+    NotBackup,
+
+    // Backup of the bot with data which can't be restored, e.g. two users with the same id
+    Invalid(String),
+
+    // Backup was written by a newer version of the bot
+    UnsupportedVersion { found: u64, supported: u32 },
+}
+
+impl From<io::Error> for BackupError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Json(error)
+    }
+}
+
+impl From<StorageError> for BackupError {
+    fn from(error: StorageError) -> Self {
+        Self::Storage(error)
+    }
+}
+
+impl From<TokenStoreError> for BackupError {
+    fn from(error: TokenStoreError) -> Self {
+        Self::TokenStore(error)
+    }
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "can't access backup file: {}", e),
+            Self::Json(e) => write!(f, "invalid backup: {}", e),
+            Self::Storage(e) => e.fmt(f),
+            Self::TokenStore(e) => write!(f, "token store error: {}", e),
+            Self::NotBackup => write!(f, "not a backup of the bot"),
+            Self::Invalid(reason) => write!(f, "invalid backup: {}", reason),
+            Self::UnsupportedVersion { found, supported } => write!(
+                f, "backup version {} is newer than supported {}, update the bot", found, supported
+            ),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Json(e) => Some(e),
+            Self::Storage(e) => Some(e),
+            Self::TokenStore(e) => Some(e),
+            Self::NotBackup | Self::Invalid(_) | Self::UnsupportedVersion { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::storage::errors::StorageError;
+use crate::storage::storage::Storage;
+use crate::storage::structs::{
+    ChatterStats, CustomCommand, MessageRecord, ModerationAction, StorageData, UserPoints, UserRecord,
+};
+use crate::utils::db::{Database, TypedBucket};
+
+// Key of the data being imported in the "import" bucket
+const PENDING_IMPORT: &str = "pending";
+
+// Storage in the kv database. Queries over time ranges scan whole buckets.
+#[derive(Clone)]
+pub struct KvStorage {
+    db: Database,
+    users: TypedBucket<UserRecord>,
+    // Keyed by zero-padded send time and id, so iteration is chronological
+    messages: TypedBucket<MessageRecord>,
+    moderation: TypedBucket<ModerationAction>,
+    points: TypedBucket<i64>,
+    commands: TypedBucket<CustomCommand>,
+    // Data of an import which isn't applied completely yet
+    imports: TypedBucket<StorageData>,
+}
+
+impl KvStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        Self::new(Database::open(path)?)
+    }
+
+    // An import interrupted by a crash is finished here
+    pub fn new(db: Database) -> Result<Self, StorageError> {
+        let storage = Self {
+            users: db.bucket("users")?,
+            messages: db.bucket("messages")?,
+            moderation: db.bucket("moderation")?,
+            points: db.bucket("points")?,
+            commands: db.bucket("commands")?,
+            imports: db.bucket("import")?,
+            db,
+        };
+        if let Some(data) = storage.imports.get(PENDING_IMPORT)? {
+            storage.apply_import(&data)?;
+        }
+        Ok(storage)
+    }
+
+    fn all_messages(&self) -> impl Iterator<Item=Result<MessageRecord, StorageError>> + '_ {
+        self.messages.iter().map(|item| Ok(item?.1))
+    }
+
+    // Replace the buckets with `data`. Can be repeated, the result is the same.
+    fn apply_import(&self, data: &StorageData) -> Result<(), StorageError> {
+        self.users.clear()?;
+        self.messages.clear()?;
+        self.moderation.clear()?;
+        self.points.clear()?;
+        self.commands.clear()?;
+        for user in &data.users {
+            self.save_user(user)?;
+        }
+        for message in &data.messages {
+            self.save_message(message)?;
+        }
+        for action in &data.moderation_actions {
+            self.save_moderation_action(action)?;
+        }
+        for points in &data.points {
+            self.points.set(&points.user_id.to_string(), &points.points)?;
+        }
+        for command in &data.commands {
+            self.save_command(command)?;
+        }
+        self.users.flush()?;
+        self.messages.flush()?;
+        self.moderation.flush()?;
+        self.points.flush()?;
+        self.commands.flush()?;
+        self.imports.remove(PENDING_IMPORT)?;
+        self.imports.flush()?;
+        Ok(())
+    }
+}
+
+fn values<V: Serialize + DeserializeOwned>(bucket: &TypedBucket<V>) -> Result<Vec<V>, StorageError> {
+    bucket.iter().map(|item| Ok(item?.1)).collect()
+}
+
+fn time_key(time: i64, id: &str) -> String {
+    format!("{:020}:{}", time, id)
+}
+
+impl Storage for KvStorage {
+    fn save_user(&self, user: &UserRecord) -> Result<(), StorageError> {
+        let key = user.user_id.to_string();
+        Ok(self.users.transaction(|txn| {
+            let first_seen = match txn.get(&key)? {
+                Some(known) => known.first_seen.min(user.first_seen),
+                None => user.first_seen,
+            };
+            txn.set(&key, &UserRecord { first_seen, ..user.clone() })
+        })?)
+    }
+
+    fn user(&self, user_id: i64) -> Result<Option<UserRecord>, StorageError> {
+        Ok(self.users.get(&user_id.to_string())?)
+    }
+
+    fn save_message(&self, message: &MessageRecord) -> Result<(), StorageError> {
+        Ok(self.messages.set(&time_key(message.sent_at, &message.message_id), message)?)
+    }
+
+    fn user_messages(&self, user_id: i64, since: i64) -> Result<Vec<MessageRecord>, StorageError> {
+        let mut messages = vec![];
+        for message in self.all_messages() {
+            let message = message?;
+            if message.user_id == user_id && message.sent_at >= since {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    fn top_chatters(&self, since: i64, limit: usize) -> Result<Vec<ChatterStats>, StorageError> {
+        let mut stats: HashMap<i64, ChatterStats> = HashMap::new();
+        for message in self.all_messages() {
+            let message = message?;
+            if message.sent_at < since {
+                continue;
+            }
+            let entry = stats.entry(message.user_id).or_insert_with(|| ChatterStats {
+                user_id: message.user_id,
+                nick_name: String::new(),
+                messages: 0,
+            });
+            // Messages are chronological, the last one has the current nickname
+            entry.nick_name = message.nick_name;
+            entry.messages += 1;
+        }
+        let mut stats: Vec<ChatterStats> = stats.into_values().collect();
+        stats.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.user_id.cmp(&b.user_id)));
+        stats.truncate(limit);
+        Ok(stats)
+    }
+
+    fn save_moderation_action(&self, action: &ModerationAction) -> Result<(), StorageError> {
+        let id = self.db.generate_id()?;
+        Ok(self.moderation.set(&time_key(action.created_at, &id.to_string()), action)?)
+    }
+
+    fn moderation_actions_by(&self, moderator_id: i64) -> Result<Vec<ModerationAction>, StorageError> {
+        let mut actions = vec![];
+        for item in self.moderation.iter() {
+            let (_, action) = item?;
+            if action.moderator_id == moderator_id {
+                actions.push(action);
+            }
+        }
+        Ok(actions)
+    }
+
+    fn points(&self, user_id: i64) -> Result<i64, StorageError> {
+        Ok(self.points.get(&user_id.to_string())?.unwrap_or(0))
+    }
+
+    fn add_points(&self, user_id: i64, delta: i64) -> Result<i64, StorageError> {
+        let key = user_id.to_string();
+        Ok(self.points.transaction(|txn| {
+            let points = txn.get(&key)?.unwrap_or(0) + delta;
+            txn.set(&key, &points)?;
+            Ok(points)
+        })?)
+    }
+
+    fn save_command(&self, command: &CustomCommand) -> Result<(), StorageError> {
+        Ok(self.commands.set(&command.name, command)?)
+    }
+
+    fn command(&self, name: &str) -> Result<Option<CustomCommand>, StorageError> {
+        Ok(self.commands.get(name)?)
+    }
+
+    fn commands(&self) -> Result<Vec<CustomCommand>, StorageError> {
+        values(&self.commands)
+    }
+
+    fn remove_command(&self, name: &str) -> Result<(), StorageError> {
+        Ok(self.commands.remove(name)?)
+    }
+
+    fn export(&self) -> Result<StorageData, StorageError> {
+        let mut points = vec![];
+        for item in self.points.iter() {
+            let (user_id, balance) = item?;
+            // Keys are written only by `add_points`, so always valid
+            if let Ok(user_id) = user_id.parse() {
+                points.push(UserPoints { user_id, points: balance });
+            }
+        }
+        Ok(StorageData {
+            users: values(&self.users)?,
+            messages: values(&self.messages)?,
+            moderation_actions: values(&self.moderation)?,
+            points,
+            commands: self.commands()?,
+        })
+    }
+
+    // The data is saved as a whole first, which is atomic, and then spread over the buckets.
+    // If that fails, the import is finished when the storage is opened next time.
+    fn import(&self, data: &StorageData) -> Result<(), StorageError> {
+        self.imports.set(PENDING_IMPORT, data)?;
+        self.imports.flush()?;
+        self.apply_import(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{sample_data, temp_kv};
+
+    #[test]
+    fn interrupted_import_is_finished_on_open() {
+        let storage = temp_kv("interrupted-import");
+        storage.save_user(&UserRecord { user_id: 9, nick_name: "old".to_string(), first_seen: 1, last_seen: 1 })
+            .unwrap();
+        // As if the bot stopped right after saving the data to import
+        storage.imports.set(PENDING_IMPORT, &sample_data()).unwrap();
+
+        let reopened = KvStorage::new(storage.db.clone()).unwrap();
+        assert_eq!(reopened.export().unwrap(), sample_data());
+        assert_eq!(reopened.imports.get(PENDING_IMPORT).unwrap(), None);
+    }
+}
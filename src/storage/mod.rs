@@ -0,0 +1,10 @@
+pub mod backup;
+pub mod errors;
+pub mod kv;
+pub mod sqlite;
+#[allow(clippy::module_inception)]
+pub mod storage;
+pub mod structs;
+#[cfg(test)]
+mod tests;
+pub mod writer;
@@ -0,0 +1,412 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::storage::errors::StorageError;
+use crate::storage::storage::Storage;
+use crate::storage::structs::{
+    ChatterStats, CustomCommand, MessageRecord, ModerationAction, StorageData, UserPoints, UserRecord,
+};
+
+// Schema changes in order. Migration `i` brings the database to version `i + 1`,
+// the current version is kept in `PRAGMA user_version`. Never edit applied migrations, append new ones.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE users (
+        user_id INTEGER PRIMARY KEY,
+        nick_name TEXT NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE messages (
+        message_id TEXT PRIMARY KEY,
+        channel_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        nick_name TEXT NOT NULL,
+        content TEXT NOT NULL,
+        sent_at INTEGER NOT NULL
+    );
+    CREATE INDEX messages_sent_at ON messages (sent_at);
+    CREATE INDEX messages_user_id ON messages (user_id, sent_at);
+    CREATE TABLE points (
+        user_id INTEGER PRIMARY KEY,
+        points INTEGER NOT NULL
+    );
+    CREATE TABLE custom_commands (
+        name TEXT PRIMARY KEY,
+        response TEXT NOT NULL,
+        created_by INTEGER,
+        updated_at INTEGER NOT NULL
+    );",
+    "CREATE TABLE moderation_actions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        channel_id INTEGER NOT NULL,
+        moderator_id INTEGER NOT NULL,
+        target_nick_name TEXT NOT NULL,
+        action TEXT NOT NULL,
+        reason TEXT,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX moderation_actions_moderator_id ON moderation_actions (moderator_id);",
+];
+
+// Storage in a SQLite file. Suits large histories, time range queries use indexes.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    // Open or create the database and apply pending migrations
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        if let Some(dir) = path.as_ref().parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    // Database which lives until the storage is dropped
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<Self, StorageError> {
+        migrate(&mut conn)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    // Schema version of the database
+    pub fn version(&self) -> Result<u32, StorageError> {
+        Ok(schema_version(&self.conn())?)
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // A panic while holding the lock can't leave a transaction open, it's rolled back on drop
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Apply every migration after the current version, each in its own transaction.
+// Databases written by a newer build are refused.
+fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+    let found = schema_version(conn)?;
+    let supported = MIGRATIONS.len() as u32;
+    if found > supported {
+        return Err(StorageError::UnsupportedVersion { found, supported });
+    }
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        let version = index as u32 + 1;
+        let apply = |conn: &mut Connection| -> rusqlite::Result<()> {
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", version)?;
+            tx.commit()
+        };
+        apply(conn).map_err(|error| StorageError::Migration { version, error })?;
+    }
+    Ok(())
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<UserRecord> {
+    Ok(UserRecord {
+        user_id: row.get("user_id")?,
+        nick_name: row.get("nick_name")?,
+        first_seen: row.get("first_seen")?,
+        last_seen: row.get("last_seen")?,
+    })
+}
+
+fn message_from_row(row: &Row) -> rusqlite::Result<MessageRecord> {
+    Ok(MessageRecord {
+        message_id: row.get("message_id")?,
+        channel_id: row.get("channel_id")?,
+        user_id: row.get("user_id")?,
+        nick_name: row.get("nick_name")?,
+        content: row.get("content")?,
+        sent_at: row.get("sent_at")?,
+    })
+}
+
+fn moderation_action_from_row(row: &Row) -> rusqlite::Result<ModerationAction> {
+    Ok(ModerationAction {
+        channel_id: row.get("channel_id")?,
+        moderator_id: row.get("moderator_id")?,
+        target_nick_name: row.get("target_nick_name")?,
+        action: row.get("action")?,
+        reason: row.get("reason")?,
+        created_at: row.get("created_at")?,
+    })
+}
+
+fn command_from_row(row: &Row) -> rusqlite::Result<CustomCommand> {
+    Ok(CustomCommand {
+        name: row.get("name")?,
+        response: row.get("response")?,
+        created_by: row.get("created_by")?,
+        updated_at: row.get("updated_at")?,
+    })
+}
+
+fn insert_user(conn: &Connection, user: &UserRecord) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO users (user_id, nick_name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (user_id) DO UPDATE SET
+            nick_name = excluded.nick_name,
+            first_seen = min(first_seen, excluded.first_seen),
+            last_seen = excluded.last_seen",
+        params![user.user_id, user.nick_name, user.first_seen, user.last_seen],
+    )?;
+    Ok(())
+}
+
+fn insert_message(conn: &Connection, message: &MessageRecord) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO messages (message_id, channel_id, user_id, nick_name, content, sent_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            message.message_id,
+            message.channel_id,
+            message.user_id,
+            message.nick_name,
+            message.content,
+            message.sent_at,
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_moderation_action(conn: &Connection, action: &ModerationAction) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO moderation_actions (channel_id, moderator_id, target_nick_name, action, reason, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            action.channel_id,
+            action.moderator_id,
+            action.target_nick_name,
+            action.action,
+            action.reason,
+            action.created_at,
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_command(conn: &Connection, command: &CustomCommand) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO custom_commands (name, response, created_by, updated_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![command.name, command.response, command.created_by, command.updated_at],
+    )?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn save_user(&self, user: &UserRecord) -> Result<(), StorageError> {
+        Ok(insert_user(&self.conn(), user)?)
+    }
+
+    fn user(&self, user_id: i64) -> Result<Option<UserRecord>, StorageError> {
+        Ok(self.conn().query_row(
+            "SELECT * FROM users WHERE user_id = ?1",
+            [user_id],
+            user_from_row,
+        ).optional()?)
+    }
+
+    fn save_message(&self, message: &MessageRecord) -> Result<(), StorageError> {
+        Ok(insert_message(&self.conn(), message)?)
+    }
+
+    fn user_messages(&self, user_id: i64, since: i64) -> Result<Vec<MessageRecord>, StorageError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT * FROM messages WHERE user_id = ?1 AND sent_at >= ?2 ORDER BY sent_at, message_id"
+        )?;
+        let messages = statement.query_map(params![user_id, since], message_from_row)?;
+        Ok(messages.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn top_chatters(&self, since: i64, limit: usize) -> Result<Vec<ChatterStats>, StorageError> {
+        let conn = self.conn();
+        // The nickname of the latest message, SQLite takes bare columns from the row of max()
+        let mut statement = conn.prepare(
+            "SELECT user_id, nick_name, count(*) AS messages, max(sent_at) FROM messages
+             WHERE sent_at >= ?1 GROUP BY user_id ORDER BY messages DESC, user_id LIMIT ?2"
+        )?;
+        let stats = statement.query_map(params![since, limit as i64], |row| {
+            Ok(ChatterStats {
+                user_id: row.get("user_id")?,
+                nick_name: row.get("nick_name")?,
+                messages: row.get("messages")?,
+            })
+        })?;
+        Ok(stats.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn save_moderation_action(&self, action: &ModerationAction) -> Result<(), StorageError> {
+        Ok(insert_moderation_action(&self.conn(), action)?)
+    }
+
+    fn moderation_actions_by(&self, moderator_id: i64) -> Result<Vec<ModerationAction>, StorageError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT * FROM moderation_actions WHERE moderator_id = ?1 ORDER BY created_at, id"
+        )?;
+        let actions = statement.query_map([moderator_id], moderation_action_from_row)?;
+        Ok(actions.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn points(&self, user_id: i64) -> Result<i64, StorageError> {
+        Ok(self.conn().query_row(
+            "SELECT points FROM points WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        ).optional()?.unwrap_or(0))
+    }
+
+    fn add_points(&self, user_id: i64, delta: i64) -> Result<i64, StorageError> {
+        Ok(self.conn().query_row(
+            "INSERT INTO points (user_id, points) VALUES (?1, ?2)
+             ON CONFLICT (user_id) DO UPDATE SET points = points + excluded.points
+             RETURNING points",
+            params![user_id, delta],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn save_command(&self, command: &CustomCommand) -> Result<(), StorageError> {
+        Ok(insert_command(&self.conn(), command)?)
+    }
+
+    fn command(&self, name: &str) -> Result<Option<CustomCommand>, StorageError> {
+        Ok(self.conn().query_row(
+            "SELECT * FROM custom_commands WHERE name = ?1",
+            [name],
+            command_from_row,
+        ).optional()?)
+    }
+
+    fn commands(&self) -> Result<Vec<CustomCommand>, StorageError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare("SELECT * FROM custom_commands ORDER BY name")?;
+        let commands = statement.query_map([], command_from_row)?;
+        Ok(commands.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn remove_command(&self, name: &str) -> Result<(), StorageError> {
+        self.conn().execute("DELETE FROM custom_commands WHERE name = ?1", [name])?;
+        Ok(())
+    }
+
+    fn export(&self) -> Result<StorageData, StorageError> {
+        let conn = self.conn();
+        let users = conn.prepare("SELECT * FROM users ORDER BY user_id")?
+            .query_map([], user_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        let messages = conn.prepare("SELECT * FROM messages ORDER BY sent_at, message_id")?
+            .query_map([], message_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        let moderation_actions = conn.prepare("SELECT * FROM moderation_actions ORDER BY created_at, id")?
+            .query_map([], moderation_action_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        let points = conn.prepare("SELECT * FROM points ORDER BY user_id")?
+            .query_map([], |row| Ok(UserPoints { user_id: row.get("user_id")?, points: row.get("points")? }))?
+            .collect::<rusqlite::Result<_>>()?;
+        let commands = conn.prepare("SELECT * FROM custom_commands ORDER BY name")?
+            .query_map([], command_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(StorageData { users, messages, moderation_actions, points, commands })
+    }
+
+    // In one transaction, a failed import changes nothing
+    fn import(&self, data: &StorageData) -> Result<(), StorageError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "DELETE FROM users;
+             DELETE FROM messages;
+             DELETE FROM moderation_actions;
+             DELETE FROM points;
+             DELETE FROM custom_commands;"
+        )?;
+        for user in &data.users {
+            insert_user(&tx, user)?;
+        }
+        for message in &data.messages {
+            insert_message(&tx, message)?;
+        }
+        for action in &data.moderation_actions {
+            insert_moderation_action(&tx, action)?;
+        }
+        for points in &data.points {
+            tx.execute(
+                "INSERT OR REPLACE INTO points (user_id, points) VALUES (?1, ?2)",
+                params![points.user_id, points.points],
+            )?;
+        }
+        for command in &data.commands {
+            insert_command(&tx, command)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_database_is_current() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        assert_eq!(storage.version().unwrap(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn old_database_is_migrated() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        insert_user(&conn, &UserRecord { user_id: 1, nick_name: "one".to_string(), first_seen: 1, last_seen: 2 })
+            .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len() as u32);
+        let storage = SqliteStorage { conn: Mutex::new(conn) };
+        assert_eq!(storage.user(1).unwrap().unwrap().nick_name, "one");
+        assert!(storage.moderation_actions_by(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-newer-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as u32 + 1).unwrap();
+        drop(conn);
+
+        let error = SqliteStorage::open(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            error,
+            StorageError::UnsupportedVersion { found, supported }
+                if found == MIGRATIONS.len() as u32 + 1 && supported == MIGRATIONS.len() as u32
+        ));
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        // Makes the second migration fail
+        conn.execute_batch("CREATE TABLE moderation_actions (id INTEGER)").unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+
+        let error = migrate(&mut conn).unwrap_err();
+        assert!(matches!(error, StorageError::Migration { version: 2, .. }));
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+    }
+}
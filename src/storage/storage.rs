@@ -0,0 +1,63 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::storage::errors::StorageError;
+use crate::storage::kv::KvStorage;
+use crate::storage::sqlite::SqliteStorage;
+use crate::storage::structs::{ChatterStats, CustomCommand, MessageRecord, ModerationAction, StorageData, UserRecord};
+use crate::utils::config::{StorageSettings, SETTINGS};
+
+// Persistent state of the bot
+pub trait Storage: Send + Sync {
+    // Add the user or update the nickname and `last_seen`. `first_seen` of a known user is kept.
+    fn save_user(&self, user: &UserRecord) -> Result<(), StorageError>;
+
+    fn user(&self, user_id: i64) -> Result<Option<UserRecord>, StorageError>;
+
+    fn save_message(&self, message: &MessageRecord) -> Result<(), StorageError>;
+
+    // Messages of the user sent at `since` or later, oldest first
+    fn user_messages(&self, user_id: i64, since: i64) -> Result<Vec<MessageRecord>, StorageError>;
+
+    // Users who sent the most messages at `since` or later
+    fn top_chatters(&self, since: i64, limit: usize) -> Result<Vec<ChatterStats>, StorageError>;
+
+    fn save_moderation_action(&self, action: &ModerationAction) -> Result<(), StorageError>;
+
+    // Actions done by the moderator, oldest first
+    fn moderation_actions_by(&self, moderator_id: i64) -> Result<Vec<ModerationAction>, StorageError>;
+
+    fn points(&self, user_id: i64) -> Result<i64, StorageError>;
+
+    // Add `delta` (may be negative) to the points of the user atomically and return the new balance
+    fn add_points(&self, user_id: i64, delta: i64) -> Result<i64, StorageError>;
+
+    fn save_command(&self, command: &CustomCommand) -> Result<(), StorageError>;
+
+    fn command(&self, name: &str) -> Result<Option<CustomCommand>, StorageError>;
+
+    // All commands ordered by name
+    fn commands(&self) -> Result<Vec<CustomCommand>, StorageError>;
+
+    fn remove_command(&self, name: &str) -> Result<(), StorageError>;
+
+    // Everything stored, records of each kind in the order they are returned by other methods
+    fn export(&self) -> Result<StorageData, StorageError>;
+
+    // Replace everything stored with `data`. A failed import doesn't leave the data mixed:
+    // SQLite keeps the old data, kv finishes the import when opened next time.
+    fn import(&self, data: &StorageData) -> Result<(), StorageError>;
+}
+
+// Backend selected by `storage` in settings
+pub fn configured_storage() -> Result<Arc<dyn Storage>, StorageError> {
+    open_storage(&SETTINGS.storage, &SETTINGS.data_dir)
+}
+
+// Relative paths in `settings` are inside `data_dir`
+pub fn open_storage(settings: &StorageSettings, data_dir: &Path) -> Result<Arc<dyn Storage>, StorageError> {
+    Ok(match settings {
+        StorageSettings::Kv { path } => Arc::new(KvStorage::open(data_dir.join(path))?),
+        StorageSettings::Sqlite { path } => Arc::new(SqliteStorage::open(data_dir.join(path))?),
+    })
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+// Chat user the bot has seen
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserRecord {
+    pub user_id: i64,
+    pub nick_name: String,
+    // Unix time in seconds
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageRecord {
+    pub message_id: String,
+    pub channel_id: i64,
+    pub user_id: i64,
+    pub nick_name: String,
+    pub content: String,
+    // Unix time in seconds
+    pub sent_at: i64,
+}
+
+// Ban, timeout, unmod and alike done in a channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModerationAction {
+    pub channel_id: i64,
+    pub moderator_id: i64,
+    pub target_nick_name: String,
+    // Chat command without "/", e.g. "ban"
+    pub action: String,
+    pub reason: Option<String>,
+    // Unix time in seconds
+    pub created_at: i64,
+}
+
+// Command added from chat, answered with `response`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CustomCommand {
+    pub name: String,
+    pub response: String,
+    pub created_by: Option<i64>,
+    // Unix time in seconds
+    pub updated_at: i64,
+}
+
+// Row of `Storage::top_chatters`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatterStats {
+    pub user_id: i64,
+    pub nick_name: String,
+    pub messages: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserPoints {
+    pub user_id: i64,
+    pub points: i64,
+}
+
+// Everything kept by a `Storage`, see `Storage::export`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct StorageData {
+    #[serde(default)]
+    pub users: Vec<UserRecord>,
+    #[serde(default)]
+    pub messages: Vec<MessageRecord>,
+    #[serde(default)]
+    pub moderation_actions: Vec<ModerationAction>,
+    #[serde(default)]
+    pub points: Vec<UserPoints>,
+    #[serde(default)]
+    pub commands: Vec<CustomCommand>,
+}
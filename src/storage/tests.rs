@@ -0,0 +1,161 @@
+// Behaviour every backend must have, checked on both of them
+
+use std::sync::Arc;
+use std::thread;
+
+use crate::storage::kv::KvStorage;
+use crate::storage::sqlite::SqliteStorage;
+use crate::storage::storage::Storage;
+use crate::storage::structs::{
+    ChatterStats, CustomCommand, MessageRecord, ModerationAction, StorageData, UserPoints, UserRecord,
+};
+
+// Fresh kv storage in a temporary directory, `name` must be unique among tests
+pub(crate) fn temp_kv(name: &str) -> KvStorage {
+    let path = std::env::temp_dir().join(format!("trovo-chatbot-kv-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    KvStorage::open(path).unwrap()
+}
+
+// Both backends, to run the same check on each
+fn backends(name: &str) -> Vec<Arc<dyn Storage>> {
+    vec![Arc::new(temp_kv(name)), Arc::new(SqliteStorage::in_memory().unwrap())]
+}
+
+fn user(user_id: i64, nick_name: &str, seen: i64) -> UserRecord {
+    UserRecord { user_id, nick_name: nick_name.to_string(), first_seen: seen, last_seen: seen }
+}
+
+fn message(id: &str, user_id: i64, nick_name: &str, sent_at: i64) -> MessageRecord {
+    MessageRecord {
+        message_id: id.to_string(),
+        channel_id: 100,
+        user_id,
+        nick_name: nick_name.to_string(),
+        content: format!("message {}", id),
+        sent_at,
+    }
+}
+
+fn command(name: &str, response: &str) -> CustomCommand {
+    CustomCommand { name: name.to_string(), response: response.to_string(), created_by: Some(1), updated_at: 10 }
+}
+
+pub(crate) fn sample_data() -> StorageData {
+    StorageData {
+        users: vec![user(1, "one", 10), user(2, "two", 20)],
+        messages: vec![message("a", 1, "one", 10), message("b", 2, "two", 20)],
+        moderation_actions: vec![ModerationAction {
+            channel_id: 100,
+            moderator_id: 1,
+            target_nick_name: "two".to_string(),
+            action: "ban".to_string(),
+            reason: Some("spam".to_string()),
+            created_at: 30,
+        }],
+        points: vec![UserPoints { user_id: 1, points: 5 }, UserPoints { user_id: 2, points: -3 }],
+        commands: vec![command("!a", "A"), command("!b", "B")],
+    }
+}
+
+#[test]
+fn known_user_keeps_first_seen() {
+    for storage in backends("users") {
+        storage.save_user(&user(1, "old", 20)).unwrap();
+        storage.save_user(&user(1, "new", 30)).unwrap();
+        storage.save_user(&user(1, "new", 10)).unwrap();
+        let saved = storage.user(1).unwrap().unwrap();
+        assert_eq!((saved.nick_name.as_str(), saved.first_seen, saved.last_seen), ("new", 10, 10));
+        assert_eq!(storage.user(2).unwrap(), None);
+    }
+}
+
+#[test]
+fn user_messages_since() {
+    for storage in backends("messages") {
+        storage.save_message(&message("c", 1, "one", 30)).unwrap();
+        storage.save_message(&message("a", 1, "one", 10)).unwrap();
+        storage.save_message(&message("b", 2, "two", 20)).unwrap();
+        let ids: Vec<String> = storage.user_messages(1, 10).unwrap().into_iter().map(|m| m.message_id).collect();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(storage.user_messages(1, 11).unwrap().len(), 1);
+    }
+}
+
+#[test]
+fn top_chatters_by_count() {
+    for storage in backends("top") {
+        storage.save_message(&message("a", 1, "one", 10)).unwrap();
+        storage.save_message(&message("b", 2, "two", 20)).unwrap();
+        storage.save_message(&message("c", 2, "renamed", 30)).unwrap();
+        storage.save_message(&message("d", 3, "three", 40)).unwrap();
+
+        let top = storage.top_chatters(0, 2).unwrap();
+        assert_eq!(top, [
+            ChatterStats { user_id: 2, nick_name: "renamed".to_string(), messages: 2 },
+            ChatterStats { user_id: 1, nick_name: "one".to_string(), messages: 1 },
+        ]);
+        let recent: Vec<i64> = storage.top_chatters(25, 10).unwrap().into_iter().map(|s| s.user_id).collect();
+        assert_eq!(recent, [2, 3]);
+    }
+}
+
+#[test]
+fn add_points_is_atomic() {
+    for storage in backends("points") {
+        assert_eq!(storage.points(1).unwrap(), 0);
+        assert_eq!(storage.add_points(1, 5).unwrap(), 5);
+        assert_eq!(storage.add_points(1, -7).unwrap(), -2);
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    storage.add_points(2, 1).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(storage.points(2).unwrap(), 100);
+    }
+}
+
+#[test]
+fn custom_commands() {
+    for storage in backends("commands") {
+        storage.save_command(&command("!b", "B")).unwrap();
+        storage.save_command(&command("!a", "A")).unwrap();
+        storage.save_command(&command("!a", "A2")).unwrap();
+        assert_eq!(storage.command("!a").unwrap().unwrap().response, "A2");
+        let names: Vec<String> = storage.commands().unwrap().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["!a", "!b"]);
+
+        storage.remove_command("!a").unwrap();
+        assert_eq!(storage.command("!a").unwrap(), None);
+    }
+}
+
+#[test]
+fn import_replaces_everything() {
+    for storage in backends("import") {
+        storage.save_user(&user(3, "gone", 5)).unwrap();
+        storage.add_points(3, 1).unwrap();
+        storage.save_command(&command("!gone", "")).unwrap();
+
+        storage.import(&sample_data()).unwrap();
+        assert_eq!(storage.export().unwrap(), sample_data());
+        assert_eq!(storage.user(3).unwrap(), None);
+        assert_eq!(storage.points(1).unwrap(), 5);
+    }
+}
+
+#[test]
+fn export_is_same_on_both_backends() {
+    let storages = backends("export");
+    for storage in &storages {
+        storage.import(&sample_data()).unwrap();
+    }
+    assert_eq!(storages[0].export().unwrap(), storages[1].export().unwrap());
+}
@@ -0,0 +1,65 @@
+use std::io;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use crate::storage::storage::Storage;
+use crate::storage::structs::{MessageRecord, UserRecord};
+
+enum Write {
+    User(UserRecord),
+    Message(MessageRecord),
+}
+
+impl std::fmt::Debug for Write {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::User(user) => write!(f, "user {}", user.user_id),
+            Self::Message(message) => write!(f, "message {}", message.message_id),
+        }
+    }
+}
+
+// Saves chat records on a dedicated thread, so the chat loop never waits for the disk.
+// Errors are printed, a record which can't be saved is skipped.
+#[derive(Debug, Clone)]
+pub struct StorageWriter {
+    writes: mpsc::Sender<Write>,
+}
+
+impl StorageWriter {
+    // The writer thread stops after all clones of the writer are dropped and the queue is written
+    pub fn spawn(storage: Arc<dyn Storage>) -> io::Result<Self> {
+        let (writes, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("storage-writer".to_string())
+            .spawn(move || write_records(received, storage))?;
+        Ok(Self { writes })
+    }
+
+    pub fn save_user(&self, user: UserRecord) {
+        self.send(Write::User(user));
+    }
+
+    pub fn save_message(&self, message: MessageRecord) {
+        self.send(Write::Message(message));
+    }
+
+    fn send(&self, write: Write) {
+        if self.writes.send(write).is_err() {
+            println!("Storage writer stopped, record is not saved");
+        }
+    }
+}
+
+fn write_records(writes: mpsc::Receiver<Write>, storage: Arc<dyn Storage>) {
+    while let Ok(write) = writes.recv() {
+        let result = match &write {
+            Write::User(user) => storage.save_user(user),
+            Write::Message(message) => storage.save_message(message),
+        };
+        if let Err(e) = result {
+            println!("Couldn't save {:?}: {}", write, e);
+        }
+    }
+}
@@ -1,21 +1,27 @@
-use config::Config;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use config::{Config, ConfigError};
 use lazy_static::lazy_static;
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::Deserialize;
 
+use crate::utils::db;
+use crate::utils::errors::{InvalidField, SettingsError};
+use crate::utils::utils::move_dir;
+
 lazy_static! {
     pub static ref SETTINGS: Settings = get_settings();
 
-    pub static ref HEADERS: HeaderMap = {
-        let mut m = HeaderMap::new();
-        m.insert("Accept", HeaderValue::from_str("application/json").unwrap());
-        m.insert("Content-Type", HeaderValue::from_str("application/json").unwrap());
-        m.insert("client-id", HeaderValue::from_str(SETTINGS.client_id.as_str()).unwrap());
-        m
-    };
+    pub static ref HEADERS: HeaderMap = client_headers(SETTINGS.client_id.as_str());
 }
 
 
+pub const API_URL: &str = "https://open-api.trovo.live/openplatform";
+pub const CHAT_URL: &str = "wss://open-chat.trovo.live/chat";
+
 // All available scopes
 pub const SCOPES: [&str; 7] = [
     "user_details_self",
@@ -28,24 +34,391 @@ pub const SCOPES: [&str; 7] = [
 ];
 
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Settings {
     pub client_id: String,
     pub client_secret: String,
     pub target_channel_name: String,
+    // Replies to chat commands, e.g. `{"!discord": "Join us at ..."}`
+    #[serde(default)]
+    pub commands: BTreeMap<String, String>,
+    // Scopes requested at login, all of `SCOPES` by default.
+    // E.g. a read-only logger needs only `["user_details_self"]`.
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    // How the authorization code gets to the bot after login
+    #[serde(default)]
+    pub oauth_flow: OAuthFlow,
+    // Directory of the bot data. Relative paths of `token_store` and `storage` are inside it,
+    // not in the working directory. By default the platform data directory,
+    // e.g. "~/.local/share/trovo-chatbot" on Linux. A relative `data_dir` is in the working directory.
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    // Where OAuth tokens are kept, the kv database by default
+    #[serde(default)]
+    pub token_store: TokenStoreSettings,
+    // Encrypt stored tokens with the key, e.g. `"token_encryption": {"key_env": "TROVO_TOKEN_KEY"}`.
+    // Plaintext tokens stored before are still read and encrypted on the next save.
+    #[serde(default)]
+    pub token_encryption: Option<TokenKeySource>,
+    // Where users, messages, points and custom commands are kept, the kv database by default.
+    // E.g. `"storage": {"type": "sqlite", "path": "bot.sqlite"}`.
+    #[serde(default)]
+    pub storage: StorageSettings,
+    // Store the text of chat messages, off by default. Users are stored anyway, for points and stats.
+    #[serde(default)]
+    pub log_messages: bool,
+}
+
+// Part of settings which can be changed while the bot is running, see `SettingsWatcher`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotSettings {
+    pub target_channel_name: String,
+    pub commands: BTreeMap<String, String>,
+}
+
+impl Settings {
+    pub fn bot(&self) -> BotSettings {
+        BotSettings {
+            target_channel_name: self.target_channel_name.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+
+    // Path inside `data_dir`, unless `path` is absolute
+    pub fn data_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.data_dir.join(path)
+    }
+
+    // The "data" directory in the working directory, where the bot kept everything before
+    // `data_dir`, if it exists and isn't the configured kv database
+    pub fn legacy_data_dir(&self) -> Option<PathBuf> {
+        let legacy = std::path::absolute(db::DB_NAME).ok()?;
+        let configured = std::path::absolute(self.data_path(db::DB_NAME)).ok()?;
+        (legacy.is_dir() && legacy != configured).then_some(legacy)
+    }
+
+    // Move the legacy "data" directory into `data_dir`, returns where it was.
+    // If `data_dir` has a database already, both are kept and the user must pick one.
+    // A "data" directory which isn't the old database is someone else's and stays in place.
+    pub fn move_legacy_data(&self) -> io::Result<Option<PathBuf>> {
+        let legacy = match self.legacy_data_dir() {
+            Some(legacy) => legacy,
+            None => return Ok(None),
+        };
+        if !db::is_legacy_database(&legacy) {
+            println!(
+                "{} isn't a database of an old version of the bot and is left in place. \
+                If it is one, move it to {} yourself",
+                legacy.display(), self.data_path(db::DB_NAME).display(),
+            );
+            return Ok(None);
+        }
+        let configured = self.data_path(db::DB_NAME);
+        if configured.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                "both {} and {} exist, remove one of them or set data_dir to the parent of the one to use",
+                legacy.display(), configured.display(),
+            )));
+        }
+        move_dir(&legacy, &configured)?;
+        Ok(Some(legacy))
+    }
+
+    // Whether anything besides `BotSettings` differs, e.g. credentials or token storage
+    pub fn differs_besides_bot(&self, other: &Settings) -> bool {
+        let without_bot = |settings: &Settings| Settings {
+            target_channel_name: String::new(),
+            commands: BTreeMap::new(),
+            ..settings.clone()
+        };
+        without_bot(self) != without_bot(other)
+    }
+}
+
+// Where the token encryption key is read from
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKeySource {
+    // Name of the environment variable
+    KeyEnv(String),
+
+    // Path to the file
+    KeyFile(String),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OAuthFlow {
+    // Listen for the redirect and read the pasted URL at the same time, whichever comes first
+    #[default]
+    Auto,
+
+    // Only listen for the redirect to localhost
+    Callback,
+
+    // Only read the redirect URL or code pasted on stdin, for servers without a browser
+    Headless,
+}
+
+// Storage of OAuth tokens, e.g. `"token_store": {"type": "file", "path": "tokens.json"}`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TokenStoreSettings {
+    // kv database in the given directory, relative to `data_dir`
+    Kv {
+        #[serde(default = "default_db_path")]
+        path: String,
+    },
+
+    // Json file. A relative path is inside `data_dir`, an absolute one is used as is.
+    // Before `data_dir` relative paths were in the working directory, such files must be moved
+    // or given by absolute path, otherwise the bot logs in again.
+    File { path: String },
+
+    // TROVO_REFRESH_TOKEN and optional TROVO_ACCESS_TOKEN, TROVO_TOKEN_EXPIRES_AT
+    // environment variables. Read-only, refreshed tokens aren't persisted.
+    Env,
+
+    // Nothing is persisted, OAuth is needed on every start
+    Memory,
+}
+
+impl Default for TokenStoreSettings {
+    fn default() -> Self {
+        Self::Kv { path: default_db_path() }
+    }
+}
+
+// Backend of `storage::Storage`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageSettings {
+    // kv database in the given directory relative to `data_dir`, shared with the token store
+    Kv {
+        #[serde(default = "default_db_path")]
+        path: String,
+    },
+
+    // SQLite database file relative to `data_dir`, created if missing
+    Sqlite {
+        #[serde(default = "default_sqlite_path")]
+        path: String,
+    },
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self::Kv { path: default_db_path() }
+    }
+}
+
+fn default_sqlite_path() -> String {
+    "data.sqlite".to_string()
+}
+
+fn default_scopes() -> Vec<String> {
+    SCOPES.iter().map(|scope| scope.to_string()).collect()
+}
+
+// Used when `data_dir` isn't set
+pub fn default_data_dir() -> PathBuf {
+    match dirs::data_dir() {
+        Some(dir) => dir.join("trovo-chatbot"),
+        None => PathBuf::from("."),
+    }
 }
 
+fn default_db_path() -> String {
+    db::DB_NAME.to_string()
+}
+
+// Settings from `init_settings`. Without it default sources are used at first access of `SETTINGS`.
+static LOADED_SETTINGS: OnceLock<Settings> = OnceLock::new();
+
 fn get_settings() -> Settings {
-    Config::builder()
-        .add_source(config::File::with_name("settings.json"))
-        .build().unwrap()
-        .try_deserialize::<Settings>().unwrap()
+    LOADED_SETTINGS.get_or_init(|| {
+        load_settings(&SettingsSources::default()).unwrap_or_else(|e| panic!("{}", e))
+    }).clone()
+}
+
+// Load and validate settings to be used as `SETTINGS`. Must be called before the first access.
+pub fn init_settings(sources: &SettingsSources) -> Result<(), SettingsError> {
+    let settings = load_settings(sources)?;
+    LOADED_SETTINGS.set(settings).map_err(|_| SettingsError::AlreadyLoaded)
+}
+
+// Where settings come from. Later layers override earlier ones:
+// defaults, settings file, `TROVO_*` environment variables, overrides.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsSources {
+    // Settings file in any format supported by `config`, e.g. json, toml or yaml.
+    // If `None`, optional "settings.*" in the working directory.
+    pub path: Option<PathBuf>,
+    // Highest priority values, e.g. from command line. Key is a dotted path like "token_store.type".
+    pub overrides: Vec<(String, String)>,
+}
+
+// Environment variables are "TROVO_" followed by the key in upper case, with "__" instead of
+// dots, e.g. TROVO_CLIENT_ID or TROVO_TOKEN_STORE__TYPE. Lists are comma-separated.
+const ENV_PREFIX: &str = "TROVO_";
+
+// Extensions of settings files `config` can read
+const SETTINGS_EXTENSIONS: [&str; 6] = ["toml", "json", "yaml", "yml", "ini", "ron"];
+
+impl SettingsSources {
+    // Settings file which is read, `None` if there is no file
+    pub fn file(&self) -> Option<PathBuf> {
+        match &self.path {
+            Some(path) => Some(path.clone()),
+            None => SETTINGS_EXTENSIONS.iter()
+                .map(|ext| Path::new("settings").with_extension(ext))
+                .find(|path| path.is_file()),
+        }
+    }
+}
+
+pub fn load_settings(sources: &SettingsSources) -> Result<Settings, SettingsError> {
+    let file = match &sources.path {
+        Some(path) => config::File::from(path.as_path()).required(true),
+        None => config::File::with_name("settings").required(false),
+    };
+    let mut builder = Config::builder().add_source(file);
+    let env = std::env::vars()
+        .filter_map(|(name, value)| {
+            let key = name.strip_prefix(ENV_PREFIX)?.to_lowercase().replace("__", ".");
+            Some((key, value))
+        });
+    for (key, value) in env.chain(sources.overrides.iter().cloned()) {
+        builder = match key.as_str() {
+            "scopes" => builder.set_override(key, split_list(&value))?,
+            _ => builder.set_override(key, value)?,
+        };
+    }
+    let config = builder.build()?;
+
+    let invalid = validate(&config);
+    if !invalid.is_empty() {
+        return Err(SettingsError::Invalid(invalid));
+    }
+    config.try_deserialize::<Settings>().map_err(|e| {
+        SettingsError::Invalid(vec![InvalidField { field: "settings".to_string(), reason: e.to_string() }])
+    })
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect()
+}
+
+// Check every field separately, so all problems are reported at once
+fn validate(config: &Config) -> Vec<InvalidField> {
+    let mut invalid = vec![];
+    let mut fail = |field: &str, reason: String| invalid.push(InvalidField {
+        field: field.to_string(),
+        reason,
+    });
+
+    for field in ["client_id", "client_secret", "target_channel_name"] {
+        match config.get_string(field) {
+            Ok(value) if value.trim().is_empty() => fail(field, "must not be empty".to_string()),
+            Ok(_) => {}
+            Err(ConfigError::NotFound(_)) => fail(field, "missing".to_string()),
+            Err(e) => fail(field, e.to_string()),
+        }
+    }
+    match optional::<BTreeMap<String, String>>(config, "commands") {
+        Ok(Some(commands)) => {
+            for name in commands.keys().filter(|name| name.is_empty() || name.contains(char::is_whitespace)) {
+                fail("commands", format!("command {:?} must be a single word", name));
+            }
+        }
+        Ok(None) => {}
+        Err(e) => fail("commands", e.to_string()),
+    }
+    match optional::<Vec<String>>(config, "scopes") {
+        Ok(Some(scopes)) => {
+            for scope in scopes.iter().filter(|scope| !SCOPES.contains(&scope.as_str())) {
+                fail("scopes", format!("unknown scope {:?}, available are: {}", scope, SCOPES.join(", ")));
+            }
+        }
+        Ok(None) => {}
+        Err(e) => fail("scopes", e.to_string()),
+    }
+    if let Err(e) = optional::<OAuthFlow>(config, "oauth_flow") {
+        fail("oauth_flow", e.to_string());
+    }
+    match optional::<PathBuf>(config, "data_dir") {
+        Ok(Some(dir)) if dir.as_os_str().is_empty() => fail("data_dir", "must not be empty".to_string()),
+        Ok(Some(dir)) if dir.exists() && !dir.is_dir() => fail("data_dir", "is not a directory".to_string()),
+        Ok(_) => {}
+        Err(e) => fail("data_dir", e.to_string()),
+    }
+    match optional::<TokenStoreSettings>(config, "token_store") {
+        Ok(Some(TokenStoreSettings::File { path })) if path.trim().is_empty() => {
+            fail("token_store.path", "must not be empty".to_string());
+        }
+        Ok(_) => {}
+        Err(e) => fail("token_store", e.to_string()),
+    }
+    if let Err(e) = optional::<TokenKeySource>(config, "token_encryption") {
+        fail("token_encryption", e.to_string());
+    }
+    match optional::<StorageSettings>(config, "storage") {
+        Ok(Some(StorageSettings::Kv { path } | StorageSettings::Sqlite { path })) if path.trim().is_empty() => {
+            fail("storage.path", "must not be empty".to_string());
+        }
+        Ok(_) => {}
+        Err(e) => fail("storage", e.to_string()),
+    }
+    invalid
+}
+
+fn optional<'de, T: Deserialize<'de>>(config: &Config, key: &str) -> Result<Option<T>, ConfigError> {
+    match config.get::<T>(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Base URLs of Trovo services. Can be pointed to a mock server in tests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    // REST API, without trailing slash
+    pub api_url: String,
+    // Chat WebSocket
+    pub chat_url: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            api_url: API_URL.to_string(),
+            chat_url: CHAT_URL.to_string(),
+        }
+    }
+}
+
+impl Endpoints {
+    // Full URL of the API method, e.g. "getuserinfo"
+    pub fn api(&self, path: &str) -> String {
+        format!("{}/{}", self.api_url, path)
+    }
 }
 
 pub fn headers() -> HeaderMap {
     HEADERS.to_owned()
 }
 
+// Same as `headers`, but with an explicit client id instead of the one from settings
+pub fn client_headers(client_id: &str) -> HeaderMap {
+    let mut m = HeaderMap::new();
+    m.insert("Accept", HeaderValue::from_str("application/json").unwrap());
+    m.insert("Content-Type", HeaderValue::from_str("application/json").unwrap());
+    m.insert("client-id", HeaderValue::from_str(client_id).unwrap());
+    m
+}
+
 pub fn authorized_headers(access_token: String) -> HeaderMap {
     let mut m = headers();
     m.insert("Authorization", HeaderValue::from_str(
@@ -53,3 +426,11 @@ pub fn authorized_headers(access_token: String) -> HeaderMap {
     ).unwrap());
     m
 }
+
+pub fn client_authorized_headers(client_id: &str, access_token: &str) -> HeaderMap {
+    let mut m = client_headers(client_id);
+    m.insert("Authorization", HeaderValue::from_str(
+        format!("OAuth {}", access_token).as_str()
+    ).unwrap());
+    m
+}
@@ -1,24 +1,349 @@
-use kv::{Config, Store};
-
-const DB_NAME: &str = "data";
-
-
-// Write value by key. Both should can be casted as u8 array
-pub fn write<'a, T>(bucket_name: &str, key: T, value: T)
-    where T: kv::Key<'a>, T: kv::Value
-{
-    let cfg = Config::new(DB_NAME);
-    let store = Store::new(cfg).unwrap();
-    let bucket = store.bucket::<T, T>(Some(bucket_name)).unwrap();
-    bucket.set(&key, &value).unwrap();
-}
-
-// Get value by key. If not exists, returns empty Vec<u8>
-pub fn read<'a, T>(bucket_name: &str, key: T) -> Option<T>
-    where T: kv::Key<'a>, T: kv::Value
-{
-    let cfg = Config::new(DB_NAME);
-    let store = Store::new(cfg).unwrap();
-    let bucket = store.bucket::<T, T>(Some(bucket_name)).unwrap();
-    return bucket.get(&key).unwrap();
-}
\ No newline at end of file
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use kv::{Bucket, Config, Raw, Store, TransactionError};
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::utils::config::SETTINGS;
+use crate::utils::errors::DatabaseError;
+use crate::utils::migrations::migrate;
+
+// Name of the kv database directory inside `data_dir`
+pub(crate) const DB_NAME: &str = "data";
+
+lazy_static! {
+    // A database directory can be opened only once per process, all handles share the store
+    static ref OPENED: Mutex<HashMap<PathBuf, Database>> = Mutex::new(HashMap::new());
+}
+
+// How values are stored in buckets. Codec errors are boxed into
+// `DatabaseError::Encode` and `DatabaseError::Decode`.
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, DatabaseError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DatabaseError>;
+}
+
+// Values as json, readable with external tools
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, DatabaseError> {
+        serde_json::to_vec(value).map_err(|e| DatabaseError::Encode(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DatabaseError> {
+        serde_json::from_slice(bytes).map_err(|e| DatabaseError::Decode(e.into()))
+    }
+}
+
+// Handle of the kv database. Cheap to clone, clones share the same store.
+#[derive(Debug, Clone)]
+pub struct Database {
+    store: Store,
+}
+
+impl Database {
+    // Open the database in the directory, or get the already opened one.
+    // The database is migrated to the current format when opened first.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database, DatabaseError> {
+        let path = std::path::absolute(path.as_ref())?;
+        let mut opened = OPENED.lock().unwrap();
+        if let Some(db) = opened.get(&path) {
+            return Ok(db.clone());
+        }
+        let db = Database {
+            store: Store::new(Config::new(&path))?,
+        };
+        migrate(&db)?;
+        opened.insert(path, db.clone());
+        Ok(db)
+    }
+
+    // The database in the "data" directory inside `data_dir`
+    pub fn default_db() -> Result<Database, DatabaseError> {
+        Self::open(SETTINGS.data_path(DB_NAME))
+    }
+
+    // Bucket storing values of type `V` as json
+    pub fn bucket<V: Serialize + DeserializeOwned>(&self, name: &str) -> Result<TypedBucket<V>, DatabaseError> {
+        self.bucket_with_codec(name)
+    }
+
+    pub fn bucket_with_codec<V: Serialize + DeserializeOwned, C: Codec>(
+        &self, name: &str,
+    ) -> Result<TypedBucket<V, C>, DatabaseError> {
+        Ok(TypedBucket {
+            bucket: self.store.bucket::<String, Raw>(Some(name))?,
+            _types: PhantomData,
+        })
+    }
+
+    // Bucket with plain string values, for data stored before typed buckets
+    pub(crate) fn string_bucket(&self, name: &str) -> Result<Bucket<'static, String, String>, DatabaseError> {
+        Ok(self.store.bucket::<String, String>(Some(name))?)
+    }
+
+    // Names of all buckets, including the default one
+    pub fn bucket_names(&self) -> Vec<String> {
+        self.store.buckets()
+    }
+
+    pub fn drop_bucket(&self, name: &str) -> Result<(), DatabaseError> {
+        Ok(self.store.drop_bucket(name)?)
+    }
+
+    // Unique id, e.g. for keys of records without a natural one
+    pub fn generate_id(&self) -> Result<u64, DatabaseError> {
+        Ok(self.store.generate_id()?)
+    }
+
+    pub fn path(&self) -> Result<&Path, DatabaseError> {
+        Ok(self.store.path()?)
+    }
+}
+
+// Whether the directory is the kv database of an old version of the bot: sled files
+// with the refresh token in the "config" bucket. It's opened without migrating
+// and closed right away, so it can be moved after.
+pub(crate) fn is_legacy_database(path: &Path) -> bool {
+    // Opening creates a database in any directory, it must look like one first
+    if !path.join("conf").is_file() || !path.join("db").is_file() {
+        return false;
+    }
+    let store = match Store::new(Config::new(path)) {
+        Ok(store) => store,
+        Err(_) => return false,
+    };
+    if !store.buckets().iter().any(|name| name == "config") {
+        return false;
+    }
+    let config = match store.bucket::<String, String>(Some("config")) {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    // Newer versions keep both keys
+    ["refresh_token", "tokens"].iter().any(|key| config.contains(&key.to_string()).unwrap_or(false))
+}
+
+// Bucket with string keys and values of type `V` encoded by `C`
+#[derive(Clone)]
+pub struct TypedBucket<V, C = Json> {
+    bucket: Bucket<'static, String, Raw>,
+    _types: PhantomData<(V, C)>,
+}
+
+impl<V: Serialize + DeserializeOwned, C: Codec> TypedBucket<V, C> {
+    pub fn get(&self, key: &str) -> Result<Option<V>, DatabaseError> {
+        match self.bucket.get(&key.to_string())? {
+            Some(raw) => Ok(Some(C::decode(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &V) -> Result<(), DatabaseError> {
+        let raw = Raw::from(C::encode(value)?);
+        Ok(self.bucket.set(&key.to_string(), &raw)?)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), DatabaseError> {
+        Ok(self.bucket.remove(&key.to_string())?)
+    }
+
+    pub fn contains(&self, key: &str) -> Result<bool, DatabaseError> {
+        Ok(self.bucket.contains(&key.to_string())?)
+    }
+
+    // All entries ordered by key
+    pub fn iter(&self) -> impl Iterator<Item=Result<(String, V), DatabaseError>> {
+        self.bucket.iter().map(decode_item::<V, C>)
+    }
+
+    // Entries which keys start with `prefix`, ordered by key
+    pub fn scan_prefix(&self, prefix: &str) -> Result<impl Iterator<Item=Result<(String, V), DatabaseError>>, DatabaseError> {
+        Ok(self.bucket.iter_prefix(&prefix.to_string())?.map(decode_item::<V, C>))
+    }
+
+    // Run `f` atomically: either all its changes are applied or none.
+    // `f` may be called several times in case of conflicts with concurrent transactions.
+    pub fn transaction<A, F>(&self, f: F) -> Result<A, DatabaseError>
+        where F: Fn(&BucketTransaction<V, C>) -> Result<A, TransactionError<DatabaseError>>
+    {
+        self.bucket
+            .transaction(|txn| {
+                f(&BucketTransaction { txn, _types: PhantomData }).map_err(|e| match e {
+                    TransactionError::Abort(e) => TransactionError::Abort(KvTransactionError(e)),
+                    TransactionError::Conflict => TransactionError::Conflict,
+                    TransactionError::Storage(e) => TransactionError::Storage(e),
+                })
+            })
+            .map_err(|e: KvTransactionError| e.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bucket.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bucket.is_empty()
+    }
+
+    pub fn clear(&self) -> Result<(), DatabaseError> {
+        Ok(self.bucket.clear()?)
+    }
+
+    // Write changes to disk now instead of in the background
+    pub fn flush(&self) -> Result<(), DatabaseError> {
+        self.bucket.flush()?;
+        Ok(())
+    }
+}
+
+fn decode_item<V: DeserializeOwned, C: Codec>(
+    item: Result<kv::Item<String, Raw>, kv::Error>,
+) -> Result<(String, V), DatabaseError> {
+    let item = item?;
+    let key: String = item.key()?;
+    let value: Raw = item.value()?;
+    Ok((key, C::decode(&value)?))
+}
+
+// Changes made in `TypedBucket::transaction`
+pub struct BucketTransaction<'a, 'b, V, C = Json> {
+    txn: kv::Transaction<'a, 'b, String, Raw>,
+    _types: PhantomData<(V, C)>,
+}
+
+impl<'a, 'b, V: Serialize + DeserializeOwned, C: Codec> BucketTransaction<'a, 'b, V, C> {
+    pub fn get(&self, key: &str) -> Result<Option<V>, TransactionError<DatabaseError>> {
+        match self.txn.get(&key.to_string()).map_err(from_kv)? {
+            Some(raw) => Ok(Some(C::decode(&raw).map_err(TransactionError::Abort)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &V) -> Result<(), TransactionError<DatabaseError>> {
+        let raw = Raw::from(C::encode(value).map_err(TransactionError::Abort)?);
+        self.txn.set(&key.to_string(), &raw).map_err(from_kv)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), TransactionError<DatabaseError>> {
+        self.txn.remove(&key.to_string()).map_err(from_kv)
+    }
+}
+
+// Stop the transaction, discarding its changes. E.g. `return abort("not enough points")`.
+pub fn abort<A>(reason: &str) -> Result<A, TransactionError<DatabaseError>> {
+    Err(TransactionError::Abort(DatabaseError::Aborted(reason.to_string())))
+}
+
+// Kv wants transaction errors convertible from storage errors of sled.
+// They are taken through `kv::Error`, so sled isn't a dependency of ours.
+struct KvTransactionError(DatabaseError);
+
+impl<E> From<E> for KvTransactionError where kv::Error: From<E> {
+    fn from(error: E) -> Self {
+        Self(kv::Error::from(error).into())
+    }
+}
+
+// Conflicts must stay conflicts, so the transaction is retried
+fn from_kv(error: TransactionError<kv::Error>) -> TransactionError<DatabaseError> {
+    match error {
+        TransactionError::Abort(e) => TransactionError::Abort(e.into()),
+        TransactionError::Conflict => TransactionError::Conflict,
+        TransactionError::Storage(e) => TransactionError::Storage(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fresh database in a temporary directory
+    fn temp_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-db-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        Database::open(path).unwrap()
+    }
+
+    // Refuses every value, like a codec which doesn't support some type
+    struct Failing;
+
+    impl Codec for Failing {
+        fn encode<T: Serialize>(_: &T) -> Result<Vec<u8>, DatabaseError> {
+            Err(DatabaseError::Encode("unsupported".into()))
+        }
+
+        fn decode<T: DeserializeOwned>(_: &[u8]) -> Result<T, DatabaseError> {
+            Err(DatabaseError::Decode("unsupported".into()))
+        }
+    }
+
+    #[test]
+    fn codec_errors_are_kept() {
+        let db = temp_db("codec");
+        let failing = db.bucket_with_codec::<u32, Failing>("numbers").unwrap();
+        assert!(matches!(failing.set("a", &1), Err(DatabaseError::Encode(_))));
+
+        db.bucket::<u32>("numbers").unwrap().set("a", &1).unwrap();
+        assert!(matches!(failing.get("a"), Err(DatabaseError::Decode(_))));
+        let error = db.bucket::<String>("numbers").unwrap().get("a").unwrap_err();
+        assert!(matches!(error, DatabaseError::Decode(_)));
+    }
+
+    // Raw kv store as written by old versions, closed when the function returns
+    fn legacy_db(name: &str, config: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-legacy-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let store = Store::new(Config::new(&path)).unwrap();
+        let bucket = store.bucket::<String, String>(Some("config")).unwrap();
+        for (key, value) in config {
+            bucket.set(&key.to_string(), &value.to_string()).unwrap();
+        }
+        bucket.flush().unwrap();
+        path
+    }
+
+    #[test]
+    fn legacy_database_is_recognized() {
+        let path = legacy_db("tokens", &[("refresh_token", "refresh")]);
+        assert!(is_legacy_database(&path));
+        std::fs::remove_dir_all(&path).unwrap();
+
+        let path = legacy_db("no-tokens", &[("other", "value")]);
+        assert!(!is_legacy_database(&path));
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn other_directory_is_not_legacy_database() {
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-not-legacy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("notes.txt"), "not a database").unwrap();
+        assert!(!is_legacy_database(&path));
+        // Nothing is created in it
+        let files = std::fs::read_dir(&path).unwrap().count();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn aborted_transaction_is_discarded() {
+        let db = temp_db("abort");
+        let bucket = db.bucket::<u32>("numbers").unwrap();
+        let result: Result<(), _> = bucket.transaction(|txn| {
+            txn.set("a", &1)?;
+            abort("changed my mind")
+        });
+        assert!(matches!(result, Err(DatabaseError::Aborted(reason)) if reason == "changed my mind"));
+        assert_eq!(bucket.get("a").unwrap(), None);
+
+        bucket.transaction(|txn| txn.set("a", &2)).unwrap();
+        assert_eq!(bucket.get("a").unwrap(), Some(2));
+    }
+}
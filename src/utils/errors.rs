@@ -0,0 +1,114 @@
+use std::{error::Error, fmt::Display, io};
+
+// Field of settings which is missing or has a wrong value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidField {
+    // Dotted path, e.g. "token_store.path"
+    pub field: String,
+    pub reason: String,
+}
+
+// Errors of loading settings
+#[derive(Debug)]
+pub enum SettingsError {
+    // Settings file can't be read or parsed
+    Source(config::ConfigError),
+
+    // Every field which is missing or invalid
+    Invalid(Vec<InvalidField>),
+
+    // `init_settings` was called after settings were loaded
+    AlreadyLoaded,
+}
+
+impl From<config::ConfigError> for SettingsError {
+    fn from(error: config::ConfigError) -> Self {
+        Self::Source(error)
+    }
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Source(e) => write!(f, "can't read settings: {}", e),
+            Self::Invalid(fields) => {
+                write!(f, "invalid settings:")?;
+                for field in fields {
+                    write!(f, "\n  {}: {}", field.field, field.reason)?;
+                }
+                Ok(())
+            }
+            Self::AlreadyLoaded => write!(f, "settings are already loaded"),
+        }
+    }
+}
+
+impl Error for SettingsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Source(e) => Some(e),
+            Self::Invalid(_) => None,
+            Self::AlreadyLoaded => None,
+        }
+    }
+}
+
+// Errors of `Database`
+#[derive(Debug)]
+pub enum DatabaseError {
+    Kv(kv::Error),
+
+    // Error opening the database directory
+    Io(io::Error),
+
+    // Value can't be encoded for storing, the error is of the bucket codec
+    Encode(Box<dyn Error + Send + Sync>),
+
+    // Stored value doesn't match the type of the bucket
+    Decode(Box<dyn Error + Send + Sync>),
+
+    // Transaction was stopped with `abort`
+    Aborted(String),
+
+    // Database was written by a newer version of the bot
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+impl From<kv::Error> for DatabaseError {
+    fn from(error: kv::Error) -> Self {
+        Self::Kv(error)
+    }
+}
+
+impl From<io::Error> for DatabaseError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kv(e) => write!(f, "database error: {}", e),
+            Self::Io(e) => write!(f, "can't open database: {}", e),
+            Self::Encode(e) => write!(f, "can't encode value: {}", e),
+            Self::Decode(e) => write!(f, "stored value is invalid: {}", e),
+            Self::Aborted(reason) => write!(f, "transaction aborted: {}", reason),
+            Self::UnsupportedVersion { found, supported } => write!(
+                f, "data format version {} is newer than supported {}, update the bot", found, supported
+            ),
+        }
+    }
+}
+
+impl Error for DatabaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Kv(e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::Encode(e) => Some(e.as_ref()),
+            Self::Decode(e) => Some(e.as_ref()),
+            Self::Aborted(_) | Self::UnsupportedVersion { .. } => None,
+        }
+    }
+}
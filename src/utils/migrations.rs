@@ -0,0 +1,124 @@
+use crate::utils::db::Database;
+use crate::utils::errors::DatabaseError;
+
+// Version of the kv database format is kept here, absent in databases made before versioning
+const META_BUCKET: &str = "meta";
+const VERSION_KEY: &str = "schema_version";
+
+type Migration = fn(&Database) -> Result<(), DatabaseError>;
+
+// Steps upgrading the kv database format. Step `i` brings the database to version `i + 1`.
+// Steps must do nothing on an empty database. Never edit released steps, append new ones.
+const MIGRATIONS: &[Migration] = &[
+    tokens_from_refresh_token,
+];
+
+// Format version written by this build
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+// Stored format version, 0 for databases made before versioning
+pub fn schema_version(db: &Database) -> Result<u32, DatabaseError> {
+    Ok(db.bucket::<u32>(META_BUCKET)?.get(VERSION_KEY)?.unwrap_or(0))
+}
+
+// Bring the database to `SCHEMA_VERSION`. Databases written by a newer build are refused,
+// this build could lose or corrupt what it doesn't know about.
+pub(crate) fn migrate(db: &Database) -> Result<(), DatabaseError> {
+    let meta = db.bucket::<u32>(META_BUCKET)?;
+    let found = schema_version(db)?;
+    if found > SCHEMA_VERSION {
+        return Err(DatabaseError::UnsupportedVersion { found, supported: SCHEMA_VERSION });
+    }
+    for (index, step) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        step(db)?;
+        // Saved after every step, so an interrupted migration continues from the failed step
+        meta.set(VERSION_KEY, &(index as u32 + 1))?;
+        meta.flush()?;
+    }
+    Ok(())
+}
+
+// The first versions kept only the refresh token as a plain string
+fn tokens_from_refresh_token(db: &Database) -> Result<(), DatabaseError> {
+    let config = db.string_bucket("config")?;
+    let tokens_key = "tokens".to_string();
+    if config.contains(&tokens_key)? {
+        return Ok(());
+    }
+    if let Some(refresh_token) = config.get(&"refresh_token".to_string())? {
+        let tokens = serde_json::json!({
+            "access_token": "",
+            "refresh_token": refresh_token,
+            "expires_at": null,
+        });
+        config.set(&tokens_key, &tokens.to_string())?;
+        config.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fresh database in a temporary directory, already migrated by `Database::open`
+    fn temp_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-migrations-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        Database::open(path).unwrap()
+    }
+
+    fn set_version(db: &Database, version: u32) {
+        db.bucket::<u32>(META_BUCKET).unwrap().set(VERSION_KEY, &version).unwrap();
+    }
+
+    #[test]
+    fn empty_database_gets_version_only() {
+        let db = temp_db("empty");
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+        let meta: Vec<_> = db.bucket::<u32>(META_BUCKET).unwrap().iter().map(Result::unwrap).collect();
+        assert_eq!(meta, [(VERSION_KEY.to_string(), SCHEMA_VERSION)]);
+        assert!(db.string_bucket("config").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refresh_token_is_converted() {
+        let db = temp_db("legacy");
+        let config = db.string_bucket("config").unwrap();
+        config.set(&"refresh_token".to_string(), &"refresh".to_string()).unwrap();
+        set_version(&db, 0);
+
+        migrate(&db).unwrap();
+        assert_eq!(schema_version(&db).unwrap(), 1);
+        let tokens = config.get(&"tokens".to_string()).unwrap().unwrap();
+        let tokens: serde_json::Value = serde_json::from_str(&tokens).unwrap();
+        assert_eq!(tokens["refresh_token"], "refresh");
+        assert_eq!(tokens["access_token"], "");
+    }
+
+    #[test]
+    fn converted_tokens_are_kept() {
+        let db = temp_db("converted");
+        let config = db.string_bucket("config").unwrap();
+        config.set(&"refresh_token".to_string(), &"old".to_string()).unwrap();
+        config.set(&"tokens".to_string(), &"{}".to_string()).unwrap();
+        set_version(&db, 0);
+
+        migrate(&db).unwrap();
+        assert_eq!(config.get(&"tokens".to_string()).unwrap().unwrap(), "{}");
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let db = temp_db("newer");
+        set_version(&db, SCHEMA_VERSION + 1);
+
+        let error = migrate(&db).unwrap_err();
+        assert!(matches!(
+            error,
+            DatabaseError::UnsupportedVersion { found, supported }
+                if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION + 1);
+    }
+}
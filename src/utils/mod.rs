@@ -1,3 +1,7 @@
 pub mod config;
 pub mod db;
+pub mod errors;
+pub mod migrations;
+pub mod reload;
+#[allow(clippy::module_inception)]
 pub mod utils;
@@ -0,0 +1,146 @@
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use tokio::{
+    select,
+    sync::watch,
+    time::interval,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::config::{load_settings, BotSettings, Settings, SettingsSources, SETTINGS};
+
+// Modification time and size of the settings file, `None` if there is no file
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn stamp(sources: &SettingsSources) -> FileStamp {
+    let metadata = fs::metadata(sources.file()?).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// Watches the settings file and publishes `BotSettings` whenever it changes.
+// Invalid edits are logged and ignored, the previous settings stay active.
+// Changes of other settings, e.g. credentials, are applied only after restart.
+// Watching stops when the handle is dropped.
+#[derive(Debug)]
+pub struct SettingsWatcher {
+    cancellation_token: CancellationToken,
+    receiver: watch::Receiver<BotSettings>,
+}
+
+impl SettingsWatcher {
+    // Check the file every `period`. `sources` must be the ones `SETTINGS` were loaded from.
+    pub fn spawn(sources: SettingsSources, period: Duration) -> Self {
+        let cancellation_token = CancellationToken::new();
+        let (sender, receiver) = watch::channel(SETTINGS.bot());
+
+        let token = cancellation_token.clone();
+        let mut last_stamp = stamp(&sources);
+        tokio::spawn(async move {
+            // Settings the current ones were loaded from, to report only new differences
+            let mut active: Settings = SETTINGS.clone();
+            let mut interval = interval(period);
+            loop {
+                select! {
+                    _ = token.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+                let new_stamp = stamp(&sources);
+                if new_stamp == last_stamp {
+                    continue;
+                }
+                last_stamp = new_stamp;
+
+                let settings = match load_settings(&sources) {
+                    Ok(settings) => settings,
+                    Err(e) => {
+                        println!("Settings weren't reloaded, keeping the previous ones: {}", e);
+                        continue;
+                    }
+                };
+                if settings.differs_besides_bot(&active) {
+                    println!("Changes of credentials and storage settings are applied after restart");
+                }
+                if settings.bot() != *sender.borrow() {
+                    println!("Settings reloaded");
+                    sender.send_replace(settings.bot());
+                }
+                active = settings;
+            }
+        });
+
+        Self {
+            cancellation_token,
+            receiver,
+        }
+    }
+
+    // Settings active now
+    pub fn current(&self) -> BotSettings {
+        self.receiver.borrow().clone()
+    }
+
+    // Receiver which is notified about every reload
+    pub fn subscribe(&self) -> watch::Receiver<BotSettings> {
+        self.receiver.clone()
+    }
+
+    // Stop watching. Automatically called on drop.
+    pub fn stop(&self) {
+        self.cancellation_token.cancel()
+    }
+}
+
+impl Drop for SettingsWatcher {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+    use crate::mock::tests::init_test_settings;
+
+    const PERIOD: Duration = Duration::from_millis(10);
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn write_settings(path: &Path, commands: &str) {
+        let text = format!(
+            r#"{{"client_id": "mock-client", "client_secret": "mock-secret", "target_channel_name": "viewer", "commands": {}}}"#,
+            commands,
+        );
+        fs::write(path, text).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changed_file_is_published() {
+        init_test_settings();
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-reload-{}.json", std::process::id()));
+        write_settings(&path, r#"{"!a": "A"}"#);
+        let sources = SettingsSources { path: Some(path.clone()), overrides: vec![] };
+        let watcher = SettingsWatcher::spawn(sources, PERIOD);
+        let mut receiver = watcher.subscribe();
+
+        write_settings(&path, r#"{"!b": "Bee"}"#);
+        timeout(WAIT, receiver.changed()).await.unwrap().unwrap();
+        let reloaded = watcher.current();
+        assert_eq!(reloaded.target_channel_name, "viewer");
+        assert_eq!(reloaded.commands.into_iter().collect::<Vec<_>>(), [("!b".to_string(), "Bee".to_string())]);
+
+        // Broken edit keeps the settings, a fixed one is applied again
+        fs::write(&path, "{\"client_id\": ").unwrap();
+        sleep(PERIOD * 10).await;
+        assert!(!receiver.has_changed().unwrap());
+        assert_eq!(watcher.current().commands.len(), 1);
+
+        write_settings(&path, r#"{"!c": "C"}"#);
+        timeout(WAIT, receiver.changed()).await.unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.current().commands.contains_key("!c"));
+    }
+}
@@ -1,3 +1,7 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
 
@@ -8,3 +12,89 @@ pub async fn random_string(size: usize) -> String {
         .map(char::from)
         .collect()
 }
+
+// Move the directory, copying it if it's on another filesystem. `to` must not exist,
+// its parent is created.
+pub fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to.display())));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // A half-made copy is removed, so the move can be retried
+    if let Err(e) = copy_dir(from, to) {
+        let _ = fs::remove_dir_all(to);
+        return Err(e);
+    }
+    fs::remove_dir_all(from)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("trovo-chatbot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn dir_is_moved_with_contents() {
+        let from = temp_path("move-from");
+        fs::create_dir_all(from.join("nested")).unwrap();
+        fs::write(from.join("nested/file"), "data").unwrap();
+        let to = temp_path("move-to").join("inside");
+
+        move_dir(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(to.join("nested/file")).unwrap(), "data");
+        fs::remove_dir_all(to.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn existing_target_is_kept() {
+        let from = temp_path("keep-from");
+        let to = temp_path("keep-to");
+        fs::create_dir_all(&from).unwrap();
+        fs::create_dir_all(&to).unwrap();
+
+        let error = move_dir(&from, &to).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(from.exists());
+        fs::remove_dir_all(&from).unwrap();
+        fs::remove_dir_all(&to).unwrap();
+    }
+
+    #[test]
+    fn copy_keeps_contents() {
+        let from = temp_path("copy-from");
+        fs::create_dir_all(from.join("nested")).unwrap();
+        fs::write(from.join("file"), "data").unwrap();
+        let to = temp_path("copy-to");
+
+        copy_dir(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(to.join("file")).unwrap(), "data");
+        assert!(to.join("nested").is_dir());
+        fs::remove_dir_all(&from).unwrap();
+        fs::remove_dir_all(&to).unwrap();
+    }
+}